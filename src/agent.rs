@@ -0,0 +1,632 @@
+//! `envgg agent`: a per-user daemon that caches secrets fetched from the
+//! keyring, so repeated runs skip the keyring (and its unlock prompts) until
+//! the cache expires or is locked.
+//!
+//! The agent listens on a Unix socket in a directory only the user can
+//! access, and also checks the peer's uid on every connection. Clients make
+//! the same checks the other way round before sending anything, so a socket
+//! planted by another user is treated as no agent at all. Cached values
+//! are kept in `mlock`ed memory and zeroed when they expire or are dropped.
+//! The agent's own request and reply buffers are zeroed after use, but not
+//! the socket reader's internal buffer.
+//!
+//! The protocol is one request per line, with every string field hex-encoded:
+//!
+//! ```text
+//! GET <namespace> <key>          -> VALUE <value> | MISS
+//! PUT <namespace> <key> <value>  -> OK
+//! FORGET <namespace> <key>       -> OK
+//! LOCK                           -> OK
+//! STATUS                         -> OK <cached count> <ttl seconds>
+//! STOP                           -> OK
+//! ```
+
+use anyhow::Context;
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Notify;
+use zeroize::{Zeroize, Zeroizing};
+
+/// How long secrets stay cached when no `--ttl` is given.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// The longest `--ttl` accepted.
+const MAX_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How often expired secrets are swept out of memory.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The agent socket: `$ENVGG_AGENT_SOCK` if set, otherwise `agent.sock` in
+/// [`socket_dir`].
+pub fn socket_path() -> PathBuf {
+    match std::env::var_os("ENVGG_AGENT_SOCK") {
+        Some(path) => PathBuf::from(path),
+        None => socket_dir().join("agent.sock"),
+    }
+}
+
+/// The directory envgg keeps its socket in: `$XDG_RUNTIME_DIR/envgg`, falling
+/// back to `envgg-<uid>` in the temp dir.
+fn socket_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) => PathBuf::from(runtime).join("envgg"),
+        // SAFETY: getuid has no preconditions and cannot fail
+        None => std::env::temp_dir().join(format!("envgg-{}", unsafe { libc::getuid() })),
+    }
+}
+
+/// Create [`socket_dir`] accessible only by the user, if it doesn't exist.
+fn create_socket_dir(dir: &Path) -> anyhow::Result<()> {
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Failed to create {}", dir.display())),
+    }
+}
+
+/// Whether `dir` is a real directory owned by the user that no one else can
+/// enter (mode 0700), so no one else can have put a socket in it.
+fn is_private_dir(dir: &Path) -> bool {
+    match fs::symlink_metadata(dir) {
+        // SAFETY: getuid has no preconditions and cannot fail
+        Ok(metadata) => {
+            metadata.is_dir()
+                && metadata.uid() == unsafe { libc::getuid() }
+                && metadata.mode() & 0o077 == 0
+        }
+        Err(_) => false,
+    }
+}
+
+/// The uid of the process on the other end of a connected Unix socket.
+fn peer_uid(stream: &impl AsRawFd) -> std::io::Result<libc::uid_t> {
+    #[cfg(target_os = "linux")]
+    {
+        // SAFETY: ucred is plain integers, for which all zeroes is valid
+        let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // SAFETY: cred and len describe a buffer of the size SO_PEERCRED fills
+        let result = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                (&mut cred as *mut libc::ucred).cast(),
+                &mut len,
+            )
+        };
+        match result {
+            0 => Ok(cred.uid),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let (mut uid, mut gid) = (0, 0);
+        // SAFETY: getpeereid only writes the two ids
+        match unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } {
+            0 => Ok(uid),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+}
+
+/// Whether the agent at `path` may be sent key names and secrets: the socket
+/// sits in a private directory and the process listening on it is the user's.
+fn is_trusted(path: &Path, stream: &impl AsRawFd) -> bool {
+    let private = path.parent().is_some_and(is_private_dir);
+    // SAFETY: getuid has no preconditions and cannot fail
+    private && peer_uid(stream).is_ok_and(|uid| uid == unsafe { libc::getuid() })
+}
+
+/// Stop other processes of the same user from attaching to this one to read
+/// its memory.
+pub fn deny_attach() {
+    #[cfg(target_os = "linux")]
+    // SAFETY: PR_SET_DUMPABLE takes a plain integer argument
+    unsafe {
+        libc::prctl(libc::PR_SET_DUMPABLE, 0);
+    }
+}
+
+/// Parse a TTL like `90`, `90s`, `15m`, `8h` or `1d`; a bare number is
+/// seconds. At most 30 days.
+pub fn parse_ttl(value: &str) -> Result<Duration, String> {
+    let (number, unit) = value.split_at(value.trim_end_matches(char::is_alphabetic).len());
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown unit `{}`, expected s, m, h or d", unit)),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration `{}`", value))?;
+
+    number
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .filter(|ttl| *ttl <= MAX_TTL)
+        .ok_or_else(|| format!("`{}` is longer than the maximum of 30d", value))
+}
+
+/// A secret held in memory that is locked against swapping, and zeroed
+/// before it is freed.
+struct LockedSecret {
+    bytes: Vec<u8>,
+}
+
+impl LockedSecret {
+    /// Take over the buffer of `value`, leaving it empty.
+    fn new(mut value: Zeroizing<String>) -> Self {
+        let bytes = std::mem::take(&mut *value).into_bytes();
+        if !bytes.is_empty() {
+            // SAFETY: the range is a live allocation owned by `bytes`. Locking
+            // is best effort: it can fail under a low RLIMIT_MEMLOCK.
+            unsafe {
+                libc::mlock(bytes.as_ptr().cast(), bytes.len());
+            }
+        }
+        Self { bytes }
+    }
+
+    fn value(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Drop for LockedSecret {
+    fn drop(&mut self) {
+        self.bytes.as_mut_slice().zeroize();
+        if !self.bytes.is_empty() {
+            // SAFETY: unlocking the same range that was locked in `new`
+            unsafe {
+                libc::munlock(self.bytes.as_ptr().cast(), self.bytes.len());
+            }
+        }
+    }
+}
+
+struct CachedSecret {
+    secret: LockedSecret,
+    expires: Instant,
+}
+
+type Cache = Arc<Mutex<HashMap<(String, String), CachedSecret>>>;
+
+/// Run the agent on the socket at `path` until it is stopped, caching each
+/// secret for `ttl`.
+pub async fn serve(path: &Path, ttl: Duration) -> anyhow::Result<()> {
+    // The parent of an explicit ENVGG_AGENT_SOCK is left as the user made it,
+    // but clients only trust a private one either way
+    let dir = socket_dir();
+    if path.parent() == Some(&dir) {
+        create_socket_dir(&dir)?;
+    }
+    if !path.parent().is_some_and(is_private_dir) {
+        anyhow::bail!(
+            "The directory of {} must be owned by you and accessible only by you (mode 0700)",
+            path.display()
+        );
+    }
+
+    // A socket left behind by an agent that did not shut down cleanly; any
+    // other file at the path is the user's and is left alone
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("{} exists and is not a socket", path.display());
+        }
+        if UnixStream::connect(path).await.is_ok() {
+            anyhow::bail!("envgg agent is already running at {}", path.display());
+        }
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+
+    let cache: Cache = Arc::default();
+    let stop = Arc::new(Notify::new());
+
+    let sweeper = tokio::spawn({
+        let cache = cache.clone();
+        async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let now = Instant::now();
+                lock(&cache).retain(|_, cached| cached.expires > now);
+            }
+        }
+    });
+
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let cache = cache.clone();
+                let stop = stop.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, &cache, &stop, ttl).await {
+                        eprintln!("envgg agent: {:#}", e);
+                    }
+                });
+            }
+            _ = stop.notified() => break,
+            _ = tokio::signal::ctrl_c() => break,
+            _ = sigterm.recv() => break,
+        }
+    }
+
+    sweeper.abort();
+    lock(&cache).clear();
+    _ = fs::remove_file(path);
+    Ok(())
+}
+
+fn lock(cache: &Cache) -> std::sync::MutexGuard<'_, HashMap<(String, String), CachedSecret>> {
+    // A panicked handler cannot leave the map half-updated, so keep going
+    cache.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    cache: &Cache,
+    stop: &Notify,
+    ttl: Duration,
+) -> anyhow::Result<()> {
+    // SAFETY: getuid has no preconditions and cannot fail
+    if stream.peer_cred()?.uid() != unsafe { libc::getuid() } {
+        anyhow::bail!("refused connection from another user");
+    }
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let line = Zeroizing::new(line);
+        let response = match handle_request(&line, cache, ttl) {
+            Ok(Request::Stop) => {
+                writer.write_all(b"OK\n").await?;
+                stop.notify_one();
+                return Ok(());
+            }
+            Ok(Request::Reply(response)) => response,
+            Err(e) => Zeroizing::new(format!("ERR {}", e)),
+        };
+        writer.write_all(response.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+    }
+
+    Ok(())
+}
+
+enum Request {
+    Reply(Zeroizing<String>),
+    Stop,
+}
+
+fn handle_request(line: &str, cache: &Cache, ttl: Duration) -> anyhow::Result<Request> {
+    let mut fields = line.split(' ');
+    let command = fields.next().unwrap_or_default();
+    let mut field = || -> anyhow::Result<Zeroizing<String>> {
+        let field = fields.next().context("missing field")?;
+        match String::from_utf8(decode_hex(field)?) {
+            Ok(text) => Ok(Zeroizing::new(text)),
+            Err(e) => {
+                e.into_bytes().zeroize();
+                anyhow::bail!("field is not UTF-8")
+            }
+        }
+    };
+
+    let response = match command {
+        "GET" => {
+            let id = (field()?.to_string(), field()?.to_string());
+            let mut cache = lock(cache);
+            match cache.get(&id) {
+                Some(cached) if cached.expires > Instant::now() => {
+                    let value = cached.secret.value();
+                    let mut response = String::with_capacity(6 + value.len() * 2);
+                    response.push_str("VALUE ");
+                    push_hex(&mut response, value);
+                    response
+                }
+                Some(_) => {
+                    cache.remove(&id);
+                    "MISS".to_string()
+                }
+                None => "MISS".to_string(),
+            }
+        }
+        "PUT" => {
+            let id = (field()?.to_string(), field()?.to_string());
+            let cached = CachedSecret {
+                secret: LockedSecret::new(field()?),
+                expires: Instant::now() + ttl,
+            };
+            lock(cache).insert(id, cached);
+            "OK".to_string()
+        }
+        "FORGET" => {
+            let id = (field()?.to_string(), field()?.to_string());
+            lock(cache).remove(&id);
+            "OK".to_string()
+        }
+        "LOCK" => {
+            lock(cache).clear();
+            "OK".to_string()
+        }
+        "STATUS" => {
+            let now = Instant::now();
+            let count = lock(cache)
+                .values()
+                .filter(|cached| cached.expires > now)
+                .count();
+            format!("OK {} {}", count, ttl.as_secs())
+        }
+        "STOP" => return Ok(Request::Stop),
+        _ => anyhow::bail!("unknown command `{}`", command),
+    };
+
+    Ok(Request::Reply(Zeroizing::new(response)))
+}
+
+/// A connection to a running agent.
+pub struct AgentClient {
+    lines: tokio::io::Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+/// What `STATUS` reports about a running agent.
+pub struct AgentStatus {
+    pub cached: usize,
+    pub ttl: Duration,
+}
+
+impl AgentClient {
+    /// Connect to the agent on the socket at `path`, or `None` when none is
+    /// running or the socket can't be trusted.
+    pub async fn connect(path: &Path) -> anyhow::Result<Option<Self>> {
+        let stream = match UnixStream::connect(path).await {
+            Ok(stream) => stream,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                return Ok(None);
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to connect to {}", path.display()));
+            }
+        };
+        if !is_trusted(path, &stream) {
+            return Ok(None);
+        }
+
+        let (reader, writer) = stream.into_split();
+        Ok(Some(Self {
+            lines: BufReader::new(reader).lines(),
+            writer,
+        }))
+    }
+
+    async fn request(&mut self, command: &str, fields: &[&str]) -> anyhow::Result<String> {
+        let mut line = command.to_string();
+        for field in fields {
+            line.push(' ');
+            line.push_str(&encode_hex(field.as_bytes()));
+        }
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).await?;
+
+        let response = self
+            .lines
+            .next_line()
+            .await?
+            .context("envgg agent closed the connection")?;
+        match response.strip_prefix("ERR ") {
+            Some(error) => anyhow::bail!("envgg agent: {}", error),
+            None => Ok(response),
+        }
+    }
+
+    /// The cached value of `key` in the keyring `namespace`, if any.
+    pub async fn get(&mut self, namespace: &str, key: &str) -> anyhow::Result<Option<String>> {
+        let response = self.request("GET", &[namespace, key]).await?;
+        match response.strip_prefix("VALUE ") {
+            Some(value) => Ok(Some(String::from_utf8(decode_hex(value)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn put(&mut self, namespace: &str, key: &str, value: &str) -> anyhow::Result<()> {
+        self.request("PUT", &[namespace, key, value]).await?;
+        Ok(())
+    }
+
+    /// Forget every cached secret.
+    pub async fn lock(&mut self) -> anyhow::Result<()> {
+        self.request("LOCK", &[]).await?;
+        Ok(())
+    }
+
+    pub async fn status(&mut self) -> anyhow::Result<AgentStatus> {
+        let response = self.request("STATUS", &[]).await?;
+        let mut fields = response.split(' ').skip(1);
+        let mut number = || -> anyhow::Result<u64> {
+            Ok(fields.next().context("malformed STATUS reply")?.parse()?)
+        };
+
+        Ok(AgentStatus {
+            cached: number()? as usize,
+            ttl: Duration::from_secs(number()?),
+        })
+    }
+
+    pub async fn stop(&mut self) -> anyhow::Result<()> {
+        self.request("STOP", &[]).await?;
+        Ok(())
+    }
+}
+
+/// Make the agent on the socket at `path`, if one is running, drop its copy
+/// of `key` after the keyring entry changed. Blocking, for keyring writers
+/// outside the async runtime.
+pub fn forget(path: &Path, namespace: &str, key: &str) -> std::io::Result<()> {
+    use std::io::{BufRead, Write};
+
+    let mut stream = match std::os::unix::net::UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    if !is_trusted(path, &stream) {
+        return Ok(());
+    }
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let request = format!(
+        "FORGET {} {}\n",
+        encode_hex(namespace.as_bytes()),
+        encode_hex(key.as_bytes())
+    );
+    stream.write_all(request.as_bytes())?;
+    std::io::BufReader::new(stream).read_line(&mut String::new())?;
+    Ok(())
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    push_hex(&mut hex, bytes);
+    hex
+}
+
+/// Append `bytes` as hex, writing into `out`'s existing capacity where it
+/// can, so no partial copies of a secret are left behind.
+fn push_hex(out: &mut String, bytes: &[u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for byte in bytes {
+        out.push(DIGITS[usize::from(byte >> 4)].into());
+        out.push(DIGITS[usize::from(byte & 0xf)].into());
+    }
+}
+
+/// Decode a hex field into a buffer allocated once at its final size.
+fn decode_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        anyhow::bail!("odd-length hex field");
+    }
+    let mut bytes = Vec::with_capacity(hex.len() / 2);
+    for pair in hex.as_bytes().chunks(2) {
+        let byte = std::str::from_utf8(pair)
+            .ok()
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok());
+        match byte {
+            Some(byte) => bytes.push(byte),
+            None => {
+                bytes.zeroize();
+                anyhow::bail!("invalid hex field");
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ttl_accepts_units_and_caps_the_length() {
+        assert_eq!(parse_ttl("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_ttl("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_ttl("30d"), Ok(MAX_TTL));
+        assert!(parse_ttl("31d").is_err());
+        assert!(parse_ttl("18446744073709551615h").is_err());
+        assert!(parse_ttl("5w").is_err());
+    }
+
+    #[tokio::test]
+    async fn agent_caches_until_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700)).unwrap();
+        let socket = dir.path().join("agent.sock");
+        let server = tokio::spawn({
+            let socket = socket.clone();
+            async move { serve(&socket, Duration::from_secs(60)).await }
+        });
+
+        let mut client = loop {
+            if let Some(client) = AgentClient::connect(&socket).await.unwrap() {
+                break client;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        client.put("envgg", "KEY", "multi\nline ü").await.unwrap();
+        assert_eq!(
+            client.get("envgg", "KEY").await.unwrap().as_deref(),
+            Some("multi\nline ü")
+        );
+        assert_eq!(client.get("other", "KEY").await.unwrap(), None);
+        assert_eq!(client.status().await.unwrap().cached, 1);
+
+        client.put("envgg", "OTHER", "x").await.unwrap();
+        tokio::task::spawn_blocking({
+            let socket = socket.clone();
+            move || forget(&socket, "envgg", "KEY")
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(client.get("envgg", "KEY").await.unwrap(), None);
+        assert_eq!(client.status().await.unwrap().cached, 1);
+
+        client.lock().await.unwrap();
+        assert_eq!(client.get("envgg", "OTHER").await.unwrap(), None);
+
+        // Anyone else could have planted the socket in a shared directory
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(AgentClient::connect(&socket).await.unwrap().is_none());
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700)).unwrap();
+
+        client.stop().await.unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!socket.exists());
+    }
+
+    #[tokio::test]
+    async fn serve_leaves_other_files_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.sock");
+        fs::write(&path, "not a socket").unwrap();
+
+        // A shared directory is refused, then a regular file in a private one
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+        let err = serve(&path, DEFAULT_TTL).await.unwrap_err();
+        assert!(err.to_string().contains("0700"), "{}", err);
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700)).unwrap();
+        let err = serve(&path, DEFAULT_TTL).await.unwrap_err();
+        assert!(err.to_string().contains("not a socket"), "{}", err);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+    }
+}
@@ -0,0 +1,316 @@
+//! `envgg backup` and `envgg restore`: every secret in the keyring namespace,
+//! in one passphrase-encrypted file.
+//!
+//! The file is a fixed header followed by the sealed payload:
+//!
+//! ```text
+//! magic     8 bytes   "ENVGGBAK"
+//! version   1 byte    1
+//! m_cost    4 bytes   Argon2id memory in KiB, little-endian
+//! t_cost    4 bytes   Argon2id iterations, little-endian
+//! p_cost    4 bytes   Argon2id parallelism, little-endian
+//! salt      16 bytes
+//! nonce     24 bytes
+//! payload   XChaCha20-Poly1305 ciphertext of a JSON [`Backup`], with the
+//!           header above as associated data
+//! ```
+//!
+//! The key is derived from the passphrase with Argon2id, using the cost
+//! parameters stored in the header, so they can be raised in later versions
+//! without breaking old files.
+
+use crate::{add_secret_to_keyring, find_secret_in_keyring, get_secret_from_keyring};
+use anyhow::Context;
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload, rand_core::RngCore};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8; 8] = b"ENVGGBAK";
+
+/// The file format version written by `envgg backup`.
+pub const VERSION: u8 = 1;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 3 * 4 + SALT_LEN + NONCE_LEN;
+
+/// Argon2id cost parameters for deriving the file key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory in KiB
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl KdfParams {
+    /// The most a backup file may ask for: 1 GiB, 16 passes and 16 lanes.
+    /// The header isn't authenticated until after the key is derived, so
+    /// anything larger is refused rather than attempted.
+    pub const MAX: Self = Self {
+        m_cost: 1024 * 1024,
+        t_cost: 16,
+        p_cost: 16,
+    };
+
+    fn check_limits(&self) -> anyhow::Result<()> {
+        let max = Self::MAX;
+        if self.m_cost > max.m_cost || self.t_cost > max.t_cost || self.p_cost > max.p_cost {
+            anyhow::bail!(
+                "the backup's key derivation costs (m={} KiB, t={}, p={}) exceed the limits of m={} KiB, t={}, p={}",
+                self.m_cost,
+                self.t_cost,
+                self.p_cost,
+                max.m_cost,
+                max.t_cost,
+                max.p_cost
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Default for KdfParams {
+    /// 64 MiB and 3 passes, which takes around a second on a laptop.
+    fn default() -> Self {
+        Self {
+            m_cost: 64 * 1024,
+            t_cost: 3,
+            p_cost: 1,
+        }
+    }
+}
+
+/// The contents of a backup file once decrypted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Backup {
+    /// The keyring service the secrets were read from
+    pub namespace: String,
+    pub secrets: IndexMap<String, String>,
+}
+
+impl Backup {
+    /// Read every one of `keys` from the keyring.
+    pub fn from_keyring(
+        namespace: &str,
+        keys: impl IntoIterator<Item = String>,
+    ) -> anyhow::Result<Self> {
+        let mut secrets = IndexMap::new();
+        for key in keys {
+            let value = get_secret_from_keyring(&key)
+                .with_context(|| format!("Failed to read {} from the keyring", key))?;
+            secrets.insert(key, value);
+        }
+        Ok(Self {
+            namespace: namespace.to_string(),
+            secrets,
+        })
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], params: KdfParams) -> anyhow::Result<Key> {
+    let argon2_params = argon2::Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+        .map_err(|e| anyhow::anyhow!("invalid key derivation parameters: {}", e))?;
+    let argon2 = Argon2::new(
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        argon2_params,
+    );
+
+    let mut key = Key::default();
+    argon2
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("failed to derive the key: {}", e))?;
+    Ok(key)
+}
+
+/// Encrypt `backup` with a key derived from `passphrase`.
+pub fn encrypt(backup: &Backup, passphrase: &str, params: KdfParams) -> anyhow::Result<Vec<u8>> {
+    let mut salt = [0; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let mut file = Vec::with_capacity(HEADER_LEN);
+    file.extend_from_slice(MAGIC);
+    file.push(VERSION);
+    for cost in [params.m_cost, params.t_cost, params.p_cost] {
+        file.extend_from_slice(&cost.to_le_bytes());
+    }
+    file.extend_from_slice(&salt);
+    file.extend_from_slice(&nonce);
+
+    let key = derive_key(passphrase, &salt, params)?;
+    let plaintext = serde_json::to_vec(backup)?;
+    let payload = Payload {
+        msg: &plaintext,
+        aad: &file,
+    };
+    let ciphertext = XChaCha20Poly1305::new(&key)
+        .encrypt(&nonce, payload)
+        .map_err(|_| anyhow::anyhow!("failed to encrypt the backup"))?;
+
+    file.extend_from_slice(&ciphertext);
+    Ok(file)
+}
+
+/// Decrypt a file written by [`encrypt`], checking its format and version.
+pub fn decrypt(file: &[u8], passphrase: &str) -> anyhow::Result<Backup> {
+    if file.len() < HEADER_LEN || !file.starts_with(MAGIC) {
+        anyhow::bail!("not an envgg backup file");
+    }
+    let (header, ciphertext) = file.split_at(HEADER_LEN);
+    let version = header[MAGIC.len()];
+    if version != VERSION {
+        anyhow::bail!(
+            "unsupported backup version {} (this envgg reads version {})",
+            version,
+            VERSION
+        );
+    }
+
+    let rest = &header[MAGIC.len() + 1..];
+    let cost = |i: usize| u32::from_le_bytes(rest[i * 4..i * 4 + 4].try_into().unwrap());
+    let params = KdfParams {
+        m_cost: cost(0),
+        t_cost: cost(1),
+        p_cost: cost(2),
+    };
+    params.check_limits()?;
+    let (salt, nonce) = rest[12..].split_at(SALT_LEN);
+
+    let key = derive_key(passphrase, salt, params)?;
+    let payload = Payload {
+        msg: ciphertext,
+        aad: header,
+    };
+    let plaintext = XChaCha20Poly1305::new(&key)
+        .decrypt(XNonce::from_slice(nonce), payload)
+        .map_err(|_| anyhow::anyhow!("wrong passphrase, or the backup file is damaged"))?;
+
+    serde_json::from_slice(&plaintext).context("the backup contents are malformed")
+}
+
+/// How `envgg restore` treats keys that are already in the keyring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreMode {
+    /// Keep the keyring's value
+    Merge,
+    /// Replace it with the backup's value
+    Overwrite,
+}
+
+/// What restoring a backup does to each of its keys.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RestorePlan {
+    /// Not in the keyring yet
+    pub added: Vec<String>,
+    /// In the keyring with a different value, which will be replaced
+    pub replaced: Vec<String>,
+    /// In the keyring with a different value, which will be kept
+    pub kept: Vec<String>,
+    /// Already in the keyring with the same value
+    pub unchanged: Vec<String>,
+}
+
+impl RestorePlan {
+    /// Compare `backup` against the keyring, without changing anything.
+    pub fn new(backup: &Backup, mode: RestoreMode) -> anyhow::Result<Self> {
+        let mut plan = Self::default();
+        for (key, value) in &backup.secrets {
+            let list = match find_secret_in_keyring(key)? {
+                None => &mut plan.added,
+                Some(existing) if existing == *value => &mut plan.unchanged,
+                Some(_) if mode == RestoreMode::Overwrite => &mut plan.replaced,
+                Some(_) => &mut plan.kept,
+            };
+            list.push(key.clone());
+        }
+        Ok(plan)
+    }
+
+    /// Store the added and replaced keys from `backup`.
+    pub fn apply(&self, backup: &Backup) -> anyhow::Result<()> {
+        for key in self.added.iter().chain(&self.replaced) {
+            add_secret_to_keyring(key, &backup.secrets[key])
+                .with_context(|| format!("Failed to store {} in the keyring", key))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decrypt_refuses_oversized_kdf_params() {
+        let max = KdfParams::MAX;
+        for costs in [
+            [u32::MAX, 1, 1],
+            [max.m_cost + 1, 1, 1],
+            [64, max.t_cost + 1, 1],
+            [64, 1, max.p_cost + 1],
+        ] {
+            let mut file = MAGIC.to_vec();
+            file.push(VERSION);
+            for cost in costs {
+                file.extend_from_slice(&cost.to_le_bytes());
+            }
+            file.resize(HEADER_LEN + 16, 0);
+
+            // Refused from the header alone, before any key derivation
+            let err = decrypt(&file, "passphrase").unwrap_err().to_string();
+            assert!(err.contains("exceed the limits"), "{:?}: {}", costs, err);
+        }
+    }
+
+    #[test]
+    fn backups_round_trip_through_the_keyring() {
+        crate::tests::mock_keyring();
+        add_secret_to_keyring("BACKUP_A", "a").unwrap();
+        add_secret_to_keyring("BACKUP_B", "multi\nline ü").unwrap();
+        let keys = ["BACKUP_A", "BACKUP_B"].map(String::from);
+        let backup = Backup::from_keyring("envgg", keys).unwrap();
+
+        // Cheap parameters; the header records them for decryption
+        let params = KdfParams {
+            m_cost: 64,
+            t_cost: 1,
+            p_cost: 1,
+        };
+        let mut file = encrypt(&backup, "correct horse", params).unwrap();
+        assert!(!String::from_utf8_lossy(&file).contains("multi"));
+        assert_eq!(decrypt(&file, "correct horse").unwrap(), backup);
+        assert!(decrypt(&file, "wrong horse").is_err());
+
+        add_secret_to_keyring("BACKUP_A", "changed").unwrap();
+        crate::delete_secret_from_keyring("BACKUP_B").unwrap();
+        let plan = RestorePlan::new(&backup, RestoreMode::Merge).unwrap();
+        assert_eq!(
+            (plan.added, plan.kept),
+            (vec!["BACKUP_B".to_string()], vec!["BACKUP_A".to_string()])
+        );
+
+        let plan = RestorePlan::new(&backup, RestoreMode::Overwrite).unwrap();
+        plan.apply(&backup).unwrap();
+        assert_eq!(get_secret_from_keyring("BACKUP_A").unwrap(), "a");
+        assert_eq!(
+            get_secret_from_keyring("BACKUP_B").unwrap(),
+            "multi\nline ü"
+        );
+
+        // Tampering with the header or payload is caught
+        file[8] = VERSION + 1;
+        assert!(
+            decrypt(&file, "correct horse")
+                .unwrap_err()
+                .to_string()
+                .contains("version")
+        );
+        file[8] = VERSION;
+        *file.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&file, "correct horse").is_err());
+    }
+}
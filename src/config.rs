@@ -0,0 +1,157 @@
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE_NAME: &str = "envgg.toml";
+
+/// Per-project settings from `envgg.toml`, found by walking up from the
+/// current directory.
+///
+/// ```toml
+/// namespace = "acme"               # keyring service (default "envgg")
+/// required = ["DATABASE_URL"]      # keys that must resolve, like `KEY!`
+/// allow = ["LANG", "SSH_AUTH_SOCK"] # passed through in `--pure` mode
+///
+/// [defaults]
+/// env = "development"              # environment when none is given
+/// strict = true
+/// verbose = false
+/// exec = false
+/// pure = false
+/// no_override = false
+/// redact = false
+///
+/// [environments.production]
+/// aliases = ["p", "prod"]
+/// files = [".env", ".env.production"]  # replaces the default layers
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub namespace: Option<String>,
+    #[serde(default)]
+    pub required: Vec<String>,
+    /// Extra parent variables passed through in `--pure` mode
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub defaults: Defaults,
+    #[serde(default)]
+    pub environments: BTreeMap<String, Environment>,
+    /// Directory containing `envgg.toml`; relative paths are resolved from here
+    #[serde(skip)]
+    pub root: PathBuf,
+}
+
+/// Values used when the matching CLI flag is not given.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Defaults {
+    pub env: Option<String>,
+    #[serde(default)]
+    pub strict: bool,
+    #[serde(default)]
+    pub verbose: bool,
+    #[serde(default)]
+    pub exec: bool,
+    #[serde(default)]
+    pub pure: bool,
+    #[serde(default)]
+    pub no_override: bool,
+    #[serde(default)]
+    pub redact: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Environment {
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    pub files: Option<Vec<PathBuf>>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut config: Config =
+            toml::from_str(&content).with_context(|| format!("Invalid {}", path.display()))?;
+        config.root = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        Ok(config)
+    }
+
+    /// Load the nearest `envgg.toml` in `start` or its ancestors, if any.
+    pub fn discover(start: &Path) -> anyhow::Result<Option<Self>> {
+        for dir in start.ancestors() {
+            let path = dir.join(CONFIG_FILE_NAME);
+            if path.is_file() {
+                return Self::load(&path).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Short aliases declared by the environments, mapped to their names.
+    pub fn aliases(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.environments.iter().flat_map(|(name, env)| {
+            env.aliases
+                .iter()
+                .map(move |alias| (alias.as_str(), name.as_str()))
+        })
+    }
+
+    /// The files declared for an environment, resolved against the project
+    /// root, or `None` to use the default layers.
+    pub fn env_files(&self, env: Option<&str>) -> Option<Vec<PathBuf>> {
+        let files = self.environments.get(env?)?.files.as_ref()?;
+        Some(files.iter().map(|file| self.root.join(file)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_defaults_and_environments() {
+        let config: Config = toml::from_str(
+            r#"
+            namespace = "acme"
+            required = ["DATABASE_URL"]
+
+            [defaults]
+            env = "development"
+            strict = true
+            redact = true
+
+            [environments.production]
+            aliases = ["p", "prod"]
+            files = [".env", ".env.production"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.namespace.as_deref(), Some("acme"));
+        assert_eq!(config.required, ["DATABASE_URL"]);
+        assert_eq!(config.defaults.env.as_deref(), Some("development"));
+        assert!(config.defaults.strict && config.defaults.redact);
+        assert!(!config.defaults.pure && !config.defaults.no_override);
+        assert_eq!(
+            config.aliases().collect::<Vec<_>>(),
+            [("p", "production"), ("prod", "production")]
+        );
+        assert_eq!(
+            config.env_files(Some("production")).unwrap(),
+            [PathBuf::from(".env"), PathBuf::from(".env.production")]
+        );
+        assert_eq!(config.env_files(Some("staging")), None);
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(toml::from_str::<Config>("[defaults]\nstrcit = true\n").is_err());
+        assert!(toml::from_str::<Config>("[environments.qa]\nfile = [\".env\"]\n").is_err());
+    }
+}
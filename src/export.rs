@@ -0,0 +1,95 @@
+use clap::ValueEnum;
+use indexmap::IndexMap;
+
+/// Output formats for `envgg export`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+    Dotenv,
+    Json,
+}
+
+/// Format `vars` as assignments that load them into a shell when evaluated,
+/// e.g. `eval "$(envgg export p)"`, or as a dotenv file or JSON object.
+pub fn format_exports(vars: &[(String, String)], format: ExportFormat) -> String {
+    if format == ExportFormat::Json {
+        let object: IndexMap<&str, &str> = vars
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        let mut json = serde_json::to_string_pretty(&object).expect("strings always serialize");
+        json.push('\n');
+        return json;
+    }
+
+    vars.iter()
+        .map(|(key, value)| {
+            let line = match format {
+                ExportFormat::Bash | ExportFormat::Zsh => {
+                    format!("export {}={}", key, posix_quote(value))
+                }
+                ExportFormat::Fish => format!("set -gx {} {}", key, fish_quote(value)),
+                ExportFormat::Powershell => {
+                    format!("$env:{} = '{}'", key, value.replace('\'', "''"))
+                }
+                ExportFormat::Dotenv => format!("{}={}", key, crate::quote_value(value)),
+                ExportFormat::Json => unreachable!(),
+            };
+            line + "\n"
+        })
+        .collect()
+}
+
+/// Shell integrations printed by `envgg hook`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Hook {
+    Direnv,
+}
+
+/// A direnv stdlib function, loaded from `direnvrc` with
+/// `eval "$(envgg hook direnv)"`, so that an `.envrc` can `use envgg [env]`.
+/// It watches the files `envgg hook direnv --watch` lists, so direnv reloads
+/// when an env file changes.
+pub const DIRENV_HOOK: &str = r#"use_envgg() {
+  local file exports
+  while IFS= read -r file; do
+    watch_file "$file"
+  done < <(envgg hook direnv --watch "$@")
+  exports="$(envgg export --format bash "$@")" || return
+  eval "$exports"
+}
+"#;
+
+/// Single-quote for POSIX shells, where nothing inside is special except the
+/// closing quote itself.
+pub fn posix_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Single-quote for fish, which also treats backslashes as escapes.
+pub fn fish_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_formats_quote_values() {
+        let vars = [("A".to_string(), "it's $HOME\\".to_string())];
+        let cases = [
+            (ExportFormat::Bash, "export A='it'\\''s $HOME\\'\n"),
+            (ExportFormat::Fish, "set -gx A 'it\\'s $HOME\\\\'\n"),
+            (ExportFormat::Powershell, "$env:A = 'it''s $HOME\\'\n"),
+            (ExportFormat::Dotenv, "A=\"it's \\$HOME\\\\\"\n"),
+            (ExportFormat::Json, "{\n  \"A\": \"it's $HOME\\\\\"\n}\n"),
+        ];
+        for (format, expected) in cases {
+            assert_eq!(format_exports(&vars, format), expected, "{:?}", format);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use crate::{EnvDocument, EnvLine, Presence};
+use indexmap::IndexMap;
+
+/// The plaintext values `envgg import` can move into the keyring: every
+/// direct `KEY=value` in the file, with its effective (last) value.
+pub fn direct_values(doc: &EnvDocument) -> IndexMap<String, String> {
+    let mut values = IndexMap::new();
+    for line in doc.lines() {
+        match line {
+            EnvLine::Direct { key, value } => {
+                values.insert(key.clone(), value.clone());
+            }
+            // A later non-direct definition wins, so there's nothing to import
+            line => {
+                if let Some(key) = line.key() {
+                    values.shift_remove(key);
+                }
+            }
+        }
+    }
+    values
+}
+
+/// The keyring key a variable is imported under: `PREFIX_KEY` with a prefix,
+/// otherwise the variable's own name.
+pub fn keyring_key(key: &str, prefix: Option<&str>) -> String {
+    format!("{}{}", prefix.unwrap_or(""), key)
+}
+
+/// Check that `--prefix` gives keyring keys that can be referenced as
+/// `$PREFIX_KEY`.
+pub fn check_prefix(prefix: &str) -> anyhow::Result<()> {
+    if crate::invalid_key_char(prefix).is_some() {
+        anyhow::bail!("invalid prefix `{}`: {}", prefix, crate::KEY_RULE);
+    }
+    Ok(())
+}
+
+/// Replace each imported variable's value in `doc` with a keyring reference:
+/// `KEY` when the keyring key is the variable's name, otherwise
+/// `KEY=$PREFIX_KEY`. Earlier direct definitions of the key are removed, as
+/// they'd leave the old plaintext in the file; every other line is left as it
+/// was.
+pub fn reference_secrets<'a>(
+    doc: &mut EnvDocument,
+    keys: impl IntoIterator<Item = &'a str>,
+    prefix: Option<&str>,
+) {
+    for key in keys {
+        let keyring_key = keyring_key(key, prefix);
+        let line = match keyring_key == key {
+            true => EnvLine::Lookup {
+                key: key.to_string(),
+                presence: Presence::Default,
+            },
+            false => EnvLine::Alias {
+                key: key.to_string(),
+                keyring_key,
+                presence: Presence::Default,
+                default: None,
+            },
+        };
+        let overridden: Vec<usize> = doc
+            .entries()
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| matches!(e.line(), EnvLine::Direct { key: k, .. } if k == key))
+            .map(|(i, _)| i)
+            .collect();
+        doc.set(line);
+        // The last one was just replaced; back to front keeps indices valid
+        for &i in overridden.iter().rev().skip(1) {
+            doc.remove_entry(i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import_references_chosen_direct_values() {
+        let content = "# keys\nKEY=old\nexport STRIPE=sk_1 # live\nPORT=80\nDUP=a\nDUP=$OTHER\nURL=\"${PORT}\"\nKEY=new\n";
+        let mut doc = EnvDocument::parse(content);
+
+        let values = direct_values(&doc);
+        assert_eq!(
+            values.into_iter().collect::<Vec<_>>(),
+            [("KEY", "new"), ("STRIPE", "sk_1"), ("PORT", "80")]
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+        );
+
+        reference_secrets(&mut doc, ["STRIPE"], Some("PROD_"));
+        reference_secrets(&mut doc, ["PORT", "KEY"], None);
+        assert_eq!(
+            doc.to_string(),
+            "# keys\nexport STRIPE=$PROD_STRIPE # live\nPORT\nDUP=a\nDUP=$OTHER\nURL=\"${PORT}\"\nKEY\n"
+        );
+        assert!(check_prefix("1X").is_err());
+    }
+}
@@ -1,99 +1,1492 @@
 use anyhow::Context;
-use indexmap::IndexSet;
+use indexmap::{IndexMap, IndexSet};
 use std::collections::HashMap;
+use std::fmt;
 use std::fs;
-use std::io::{self, BufRead};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
 const TAG: &str = "envgg";
 
+/// How many keyring lookups run at once in `get_secrets_from_keyring`.
+const KEYRING_CONCURRENCY: usize = 8;
+
+static NAMESPACE: OnceLock<String> = OnceLock::new();
+
+#[cfg(unix)]
+pub mod agent;
+pub mod backup;
+pub mod config;
+pub mod export;
+pub mod import;
+pub mod prompt;
+pub mod redact;
+pub mod run;
 pub mod ui;
 
-pub enum EnvLine {
-    Comment,
-    Alias { key: String, keyring_key: String },
-    Direct { key: String, value: String },
-    Lookup { key: String },
-}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvLine {
+    Comment,
+    Alias {
+        key: String,
+        keyring_key: String,
+        presence: Presence,
+        /// `KEY=${ALIAS:-default}` - used when the keyring has no `ALIAS`
+        default: Option<String>,
+    },
+    Direct {
+        key: String,
+        value: String,
+    },
+    Interpolated {
+        key: String,
+        parts: Vec<ValuePart>,
+    },
+    Lookup {
+        key: String,
+        presence: Presence,
+    },
+    /// `#@include path` - splice in another env file, relative to this one
+    Include {
+        path: String,
+    },
+}
+
+impl EnvLine {
+    /// The variable this line defines, if any.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            EnvLine::Comment | EnvLine::Include { .. } => None,
+            EnvLine::Alias { key, .. }
+            | EnvLine::Direct { key, .. }
+            | EnvLine::Interpolated { key, .. }
+            | EnvLine::Lookup { key, .. } => Some(key),
+        }
+    }
+}
+
+/// What to do when a keyring-backed key can't be resolved, set by a `!` or
+/// `?` after the key (`API_KEY!`, `SENTRY_DSN?=$DSN`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Presence {
+    /// Warn and skip the variable, or abort in `--strict` mode
+    #[default]
+    Default,
+    /// `KEY!` - always abort before running the command
+    Required,
+    /// `KEY?` - skip the variable silently
+    Optional,
+}
+
+impl Presence {
+    fn marker(self) -> &'static str {
+        match self {
+            Presence::Default => "",
+            Presence::Required => "!",
+            Presence::Optional => "?",
+        }
+    }
+}
+
+/// A piece of a direct value containing `${NAME}` or `$NAME` references.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValuePart {
+    Literal(String),
+    Reference(String),
+}
+
+/// A malformed entry in an env file, located by 1-based line and column.
+#[derive(Debug, Clone)]
+pub struct EnvParseError {
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub reason: String,
+    pub source_line: String,
+}
+
+impl fmt::Display for EnvParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.path.display(),
+            self.line,
+            self.column,
+            self.reason
+        )
+    }
+}
+
+impl std::error::Error for EnvParseError {}
+
+impl EnvParseError {
+    /// Render the error the way a compiler would, pointing at the offending column.
+    pub fn render(&self) -> String {
+        let gutter = " ".repeat(self.line.to_string().len());
+        format!(
+            "error: {}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}^",
+            self.reason,
+            gutter,
+            self.path.display(),
+            self.line,
+            self.column,
+            gutter,
+            self.line,
+            self.source_line,
+            gutter,
+            " ".repeat(self.column.saturating_sub(1)),
+        )
+    }
+}
+
+/// Every malformed entry found in a single env file.
+#[derive(Debug)]
+pub struct EnvFileErrors(pub Vec<EnvParseError>);
+
+impl fmt::Display for EnvFileErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, err) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", err)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for EnvFileErrors {}
+
+impl EnvFileErrors {
+    pub fn render(&self) -> String {
+        self.0
+            .iter()
+            .map(EnvParseError::render)
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+}
+
+/// Column (1-based) and reason for a line that failed to parse.
+pub type LineError = (usize, String);
+
+/// Byte offset and reason for an entry that failed to parse.
+type EntryError = (usize, String);
+
+/// Byte offset of the first character not allowed in a key, if any.
+fn invalid_key_char(key: &str) -> Option<usize> {
+    key.char_indices().find_map(|(i, c)| {
+        let allowed = c.is_ascii_alphanumeric() || c == '_';
+        (!allowed || (i == 0 && c.is_ascii_digit())).then_some(i)
+    })
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && invalid_key_char(key).is_none()
+}
+
+/// What a key may contain, for errors about keys given on the command line.
+pub(crate) const KEY_RULE: &str =
+    "it may only contain letters, digits and underscores, and must not start with a digit";
+
+/// Check that a key given on the command line can be referenced from an env
+/// file.
+pub fn check_key(key: &str) -> anyhow::Result<()> {
+    if !is_valid_key(key) {
+        anyhow::bail!("invalid key `{}`: {}", key, KEY_RULE);
+    }
+    Ok(())
+}
+
+/// 1-based character column of a byte offset.
+fn line_col(s: &str, byte_offset: usize) -> usize {
+    s[..byte_offset].chars().count() + 1
+}
+
+/// Byte offset of `sub` within `s`, where `sub` is a slice of `s`.
+fn offset_in(s: &str, sub: &str) -> usize {
+    sub.as_ptr() as usize - s.as_ptr() as usize
+}
+
+/// Strict counterpart of [`parse_env_line`]: rejects keys and aliases that are
+/// not valid environment variable names instead of guessing.
+pub fn try_parse_env_line(line: &str) -> Result<EnvLine, LineError> {
+    try_parse_entry(line).map_err(|(offset, reason)| (line_col(line, offset), reason))
+}
+
+/// Parse a single entry, which may span several lines when it holds a
+/// multi-line double-quoted value.
+fn try_parse_entry(entry: &str) -> Result<EnvLine, EntryError> {
+    let trimmed = entry.trim();
+
+    if let Some(directive) = trimmed.strip_prefix("#@include") {
+        if !directive.is_empty() && !directive.starts_with([' ', '\t']) {
+            return Ok(EnvLine::Comment);
+        }
+        let path = strip_quotes(directive.trim());
+        if path.is_empty() {
+            return Err((
+                offset_in(entry, trimmed),
+                "missing path after `#@include`".to_string(),
+            ));
+        }
+        let path = path.to_string();
+        return Ok(EnvLine::Include { path });
+    }
+
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return Ok(EnvLine::Comment);
+    }
+
+    let trimmed = strip_export(trimmed);
+    let key_end = trimmed.find('=').unwrap_or(trimmed.len());
+    let key = trimmed[..key_end].trim_end();
+
+    let (key, presence) = match key.strip_suffix('!') {
+        Some(key) => (key, Presence::Required),
+        None => match key.strip_suffix('?') {
+            Some(key) => (key, Presence::Optional),
+            None => (key, Presence::Default),
+        },
+    };
+
+    if key.is_empty() {
+        return Err((
+            offset_in(entry, trimmed),
+            "missing key before `=`".to_string(),
+        ));
+    }
+    if let Some(i) = invalid_key_char(key) {
+        return Err((
+            offset_in(entry, key) + i,
+            format!(
+                "invalid key `{}`: keys may only contain letters, digits and underscores, and must not start with a digit",
+                key
+            ),
+        ));
+    }
+
+    let marker_offset = offset_in(entry, key) + key.len();
+    let key = key.to_string();
+
+    // Case: KEY only (no =) - lookup from keyring
+    if key_end == trimmed.len() {
+        return Ok(EnvLine::Lookup { key, presence });
+    }
+
+    // Inline comments are only recognised after unquoted values; quoted
+    // values handle them in `parse_value`
+    let raw = &trimmed[key_end + 1..];
+    let value = raw.trim_start();
+
+    // Case: KEY=${OTHER:-"default"} - a quoted default may hold `}` or ` #`,
+    // so the comment is only looked for after the closing brace
+    if let Some(body) = value.strip_prefix("${")
+        && let Some((keyring_key, default)) = body.split_once(":-")
+        && default.starts_with(['"', '\''])
+    {
+        check_keyring_key(entry, keyring_key)?;
+        let offset = offset_in(entry, default);
+        let (parts, end) = parse_quoted(default).map_err(|(i, reason)| (offset + i, reason))?;
+        let Some(rest) = default[end + 1..].strip_prefix('}') else {
+            return Err((
+                offset + end + 1,
+                "expected `}` after the quoted default".to_string(),
+            ));
+        };
+        let rest = rest.trim_start();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err((
+                offset_in(entry, rest),
+                "unexpected characters after `}`".to_string(),
+            ));
+        }
+
+        let mut literal = String::new();
+        for part in parts {
+            match part {
+                ValuePart::Literal(text) => literal.push_str(&text),
+                ValuePart::Reference(_) => {
+                    return Err((
+                        offset,
+                        "defaults cannot reference variables; write `\\$` for a literal `$`"
+                            .to_string(),
+                    ));
+                }
+            }
+        }
+        return Ok(EnvLine::Alias {
+            key,
+            keyring_key: keyring_key.to_string(),
+            presence,
+            default: Some(literal),
+        });
+    }
+
+    let value = if value.starts_with(['"', '\'']) {
+        value
+    } else {
+        strip_inline_comment(raw).trim()
+    };
+
+    // Case: KEY=$OTHER, KEY=${OTHER} or KEY=${OTHER:-default} - alias for keyring lookup
+    if let Some(val) = value.strip_prefix('$') {
+        let val = val.trim();
+        let braced = val.strip_prefix('{').and_then(|v| v.strip_suffix('}'));
+        let (keyring_key, default) = match braced.map(|v| v.split_once(":-")) {
+            Some(Some((keyring_key, default))) => (keyring_key, Some(default.to_string())),
+            Some(None) => (braced.unwrap_or(val), None),
+            None => (val, None),
+        };
+
+        if keyring_key.is_empty() {
+            return Err((
+                offset_in(entry, value),
+                "missing keyring key after `$`".to_string(),
+            ));
+        }
+        check_keyring_key(entry, keyring_key)?;
+
+        let keyring_key = keyring_key.to_string();
+        return Ok(EnvLine::Alias {
+            key,
+            keyring_key,
+            presence,
+            default,
+        });
+    }
+
+    if presence != Presence::Default {
+        return Err((
+            marker_offset,
+            "`!` and `?` markers only apply to keyring lookups and aliases".to_string(),
+        ));
+    }
+
+    // Case: KEY=value - direct value, possibly quoted or interpolated
+    let parts = parse_value(value).map_err(|(i, reason)| (offset_in(entry, value) + i, reason))?;
+
+    if parts.iter().any(|p| matches!(p, ValuePart::Reference(_))) {
+        return Ok(EnvLine::Interpolated { key, parts });
+    }
+
+    let value = parts
+        .into_iter()
+        .filter_map(|part| match part {
+            ValuePart::Literal(text) => Some(text),
+            ValuePart::Reference(_) => None,
+        })
+        .collect();
+
+    Ok(EnvLine::Direct { key, value })
+}
+
+fn check_keyring_key(entry: &str, keyring_key: &str) -> Result<(), EntryError> {
+    match invalid_key_char(keyring_key) {
+        Some(i) => Err((
+            offset_in(entry, keyring_key) + i,
+            format!("invalid keyring key `{}`", keyring_key),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn strip_quotes(value: &str) -> &str {
+    let quoted = value.len() >= 2
+        && ((value.starts_with('"') && value.ends_with('"'))
+            || (value.starts_with('\'') && value.ends_with('\'')));
+    if quoted {
+        &value[1..value.len() - 1]
+    } else {
+        value
+    }
+}
+
+/// Drop a shell `export ` prefix so env files can also be `source`d.
+fn strip_export(line: &str) -> &str {
+    match line.strip_prefix("export") {
+        Some(rest) if rest.starts_with([' ', '\t']) => rest.trim_start(),
+        _ => line,
+    }
+}
+
+/// Lenient parser: anything without `=` is treated as a keyring lookup.
+/// Use [`try_parse_env_line`] to reject malformed entries.
+pub fn parse_env_line(line: &str) -> EnvLine {
+    try_parse_entry(line).unwrap_or_else(|_| guess_env_line(line))
+}
+
+/// Best-effort interpretation of an entry that failed strict parsing.
+fn guess_env_line(line: &str) -> EnvLine {
+    let trimmed = strip_export(line.trim());
+
+    if trimmed.starts_with('#') {
+        return EnvLine::Comment;
+    }
+
+    // Check for KEY=VALUE format
+    if let Some(pos) = trimmed.find('=') {
+        let key = trimmed[..pos].trim().to_string();
+        let value = trimmed[pos + 1..].trim().to_string();
+
+        // Case: KEY=$OTHER - alias for keyring lookup
+        if let Some(val) = value.strip_prefix('$') {
+            let keyring_key = val.trim().to_string();
+            EnvLine::Alias {
+                key,
+                keyring_key,
+                presence: Presence::Default,
+                default: None,
+            }
+        } else {
+            // Case: KEY=value - direct value assignment
+            // Remove quotes if present
+            let value = strip_quotes(&value).to_string();
+
+            EnvLine::Direct { key, value }
+        }
+    } else {
+        // Case: KEY only (no =) - lookup from keyring
+        let key = trimmed.to_string();
+        EnvLine::Lookup {
+            key,
+            presence: Presence::Default,
+        }
+    }
+}
+
+/// Cut an unquoted value at the first `#` preceded by whitespace.
+fn strip_inline_comment(value: &str) -> &str {
+    value
+        .char_indices()
+        .zip(value.chars().skip(1))
+        .find(|&((_, c), next)| c.is_whitespace() && next == '#')
+        .map_or(value, |((i, _), _)| &value[..i])
+}
+
+/// Parse the right-hand side of `KEY=...`, with leading whitespace and any
+/// inline comment after an unquoted value already removed.
+///
+/// - `'single'` quotes are fully literal.
+/// - `"double"` quotes may span several lines, support `${NAME}` references
+///   and the `\n`, `\t`, `\"`, `\$` and `\\` escapes.
+/// - Unquoted values support `${NAME}` references, with `\$` for a literal `$`.
+///
+/// Errors carry the byte offset within `value`.
+fn parse_value(value: &str) -> Result<Vec<ValuePart>, EntryError> {
+    if !value.starts_with(['"', '\'']) {
+        return parse_template(value);
+    }
+    let (parts, end) = parse_quoted(value)?;
+
+    // Only whitespace and a comment may follow the closing quote
+    let rest = value[end + 1..].trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        let quote = if value.starts_with('"') {
+            "double-quoted"
+        } else {
+            "single-quoted"
+        };
+        return Err((
+            offset_in(value, rest),
+            format!("unexpected characters after {} value", quote),
+        ));
+    }
+
+    Ok(parts)
+}
+
+/// Parse the single- or double-quoted value at the start of `value`, returning
+/// its parts and the byte offset of the closing quote.
+fn parse_quoted(value: &str) -> Result<(Vec<ValuePart>, usize), EntryError> {
+    let (parts, end, quote) = match value.chars().next() {
+        Some('\'') => {
+            let end = value[1..].find('\'').map(|i| i + 1);
+            let literal = &value[1..end.unwrap_or(value.len())];
+            let parts = if literal.is_empty() {
+                Vec::new()
+            } else {
+                vec![ValuePart::Literal(literal.to_string())]
+            };
+            (parts, end, "single-quoted")
+        }
+        _ => {
+            let (parts, end) = scan_parts(value, 1, Some('"'))?;
+            (parts, end, "double-quoted")
+        }
+    };
+
+    match end {
+        Some(end) => Ok((parts, end)),
+        None => Err((0, format!("unterminated {} value", quote))),
+    }
+}
+
+/// Split an unquoted value into literal text and `${NAME}` / `$NAME`
+/// references. A `$` that is not followed by a name or `{` is kept
+/// literally, as is one escaped as `\$`.
+/// Errors carry the byte offset of the problem within `value`.
+pub fn parse_template(value: &str) -> Result<Vec<ValuePart>, (usize, String)> {
+    scan_parts(value, 0, None).map(|(parts, _)| parts)
+}
+
+/// Scan `value` from byte `start` into literal text and references. With a
+/// `quote`, double-quote escapes apply and scanning stops at the closing
+/// quote, whose offset is returned if found.
+fn scan_parts(
+    value: &str,
+    start: usize,
+    quote: Option<char>,
+) -> Result<(Vec<ValuePart>, Option<usize>), EntryError> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = value[start..]
+        .char_indices()
+        .map(|(i, c)| (start + i, c))
+        .peekable();
+    let mut end = None;
+
+    while let Some((i, c)) = chars.next() {
+        if Some(c) == quote {
+            end = Some(i);
+            break;
+        }
+
+        if c == '\\' {
+            let escaped = match quote {
+                Some(_) => chars.next_if(|&(_, c)| matches!(c, 'n' | 'r' | 't' | '"' | '$' | '\\')),
+                None => chars.next_if(|&(_, c)| c == '$'),
+            };
+            match escaped {
+                Some((_, 'n')) => literal.push('\n'),
+                Some((_, 'r')) => literal.push('\r'),
+                Some((_, 't')) => literal.push('\t'),
+                Some((_, c)) => literal.push(c),
+                None => literal.push('\\'),
+            }
+            continue;
+        }
+
+        if c != '$' {
+            literal.push(c);
+            continue;
+        }
+
+        let name = match chars.peek() {
+            Some(&(_, '{')) => {
+                let name_start = i + 2;
+                let len = value[name_start..]
+                    .find('}')
+                    .ok_or((i, "unterminated `${`, expected a closing `}`".to_string()))?;
+                let name = &value[name_start..name_start + len];
+                if !is_valid_key(name) {
+                    return Err((
+                        name_start,
+                        format!("invalid variable name `{}` in `${{}}`", name),
+                    ));
+                }
+                // Skip past the closing brace
+                while chars.next_if(|&(j, _)| j <= name_start + len).is_some() {}
+                name.to_string()
+            }
+            Some(&(_, c)) if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = String::new();
+                while let Some((_, c)) =
+                    chars.next_if(|&(_, c)| c.is_ascii_alphanumeric() || c == '_')
+                {
+                    name.push(c);
+                }
+                name
+            }
+            _ => {
+                literal.push('$');
+                continue;
+            }
+        };
+
+        if !literal.is_empty() {
+            parts.push(ValuePart::Literal(std::mem::take(&mut literal)));
+        }
+        parts.push(ValuePart::Reference(name));
+    }
+
+    if !literal.is_empty() {
+        parts.push(ValuePart::Literal(literal));
+    }
+
+    Ok((parts, end))
+}
+
+/// Whether `line` opens a double-quoted value without closing it, meaning the
+/// value continues on the following lines.
+fn opens_multiline_value(line: &str) -> bool {
+    if line.trim_start().starts_with('#') {
+        return false;
+    }
+    let Some(eq) = line.find('=') else {
+        return false;
+    };
+    let Some(rest) = line[eq + 1..].trim_start().strip_prefix('"') else {
+        return false;
+    };
+
+    let mut escaped = false;
+    for c in rest.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return false,
+            _ => {}
+        }
+    }
+    true
+}
+
+/// Split file contents into entries, joining the lines of multi-line
+/// double-quoted values. Entries keep their line terminators, so they
+/// concatenate back to `content`.
+fn split_raw_entries(content: &str) -> Vec<&str> {
+    let mut entries = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+
+    while start < content.len() {
+        let line_end = content[pos..]
+            .find('\n')
+            .map_or(content.len(), |i| pos + i + 1);
+        let entry = &content[start..line_end];
+
+        if line_end < content.len() && opens_multiline_value(entry) {
+            pos = line_end;
+            continue;
+        }
+
+        entries.push(entry);
+        start = line_end;
+        pos = start;
+    }
+
+    entries
+}
+
+/// Entries without their line terminator, each with its byte offset.
+fn split_entries(content: &str) -> Vec<(usize, &str)> {
+    split_raw_entries(content)
+        .into_iter()
+        .map(|entry| {
+            let offset = offset_in(content, entry);
+            (offset, entry.strip_suffix('\n').unwrap_or(entry))
+        })
+        .collect()
+}
+
+fn read_env_file_contents(path: &PathBuf) -> anyhow::Result<String> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    Ok(content.replace("\r\n", "\n"))
+}
+
+/// Why the values of an env file could not be interpolated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterpolationError {
+    /// `key` references a variable that is neither in the file nor in the environment.
+    Undefined { key: String, reference: String },
+    /// `key` references a variable from the file that could not be resolved.
+    Unresolved { key: String, reference: String },
+    /// The listed keys reference each other in a loop.
+    Cycle(Vec<String>),
+}
+
+impl fmt::Display for InterpolationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpolationError::Undefined { key, reference } => write!(
+                f,
+                "'{}' references undefined variable '{}' (not in the env file or the environment)",
+                key, reference
+            ),
+            InterpolationError::Unresolved { key, reference } => write!(
+                f,
+                "'{}' references '{}', which could not be resolved",
+                key, reference
+            ),
+            InterpolationError::Cycle(keys) => {
+                write!(f, "circular reference: {}", keys.join(" -> "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for InterpolationError {}
+
+/// Resolve interpolated values in dependency order, adding them to `resolved`.
+///
+/// References are looked up first among the file's own keys (`declared`
+/// holds every key defined in the file, including ones that failed to
+/// resolve), then through `lookup_env`. A key referencing itself, like
+/// `PATH="${PATH}:./bin"`, gets its value from `lookup_env`.
+pub fn interpolate(
+    templates: &IndexMap<String, Vec<ValuePart>>,
+    declared: &IndexSet<String>,
+    resolved: &mut IndexMap<String, String>,
+    lookup_env: impl Fn(&str) -> Option<String>,
+) -> Result<(), InterpolationError> {
+    fn visit(
+        key: &str,
+        templates: &IndexMap<String, Vec<ValuePart>>,
+        declared: &IndexSet<String>,
+        resolved: &mut IndexMap<String, String>,
+        lookup_env: &dyn Fn(&str) -> Option<String>,
+        stack: &mut Vec<String>,
+    ) -> Result<(), InterpolationError> {
+        if resolved.contains_key(key) {
+            return Ok(());
+        }
+        if let Some(pos) = stack.iter().position(|k| k == key) {
+            let mut cycle = stack[pos..].to_vec();
+            cycle.push(key.to_string());
+            return Err(InterpolationError::Cycle(cycle));
+        }
+        let Some(parts) = templates.get(key) else {
+            return Ok(());
+        };
+
+        stack.push(key.to_string());
+        let mut value = String::new();
+        for part in parts {
+            match part {
+                ValuePart::Literal(text) => value.push_str(text),
+                ValuePart::Reference(name) if name == key => match lookup_env(name) {
+                    Some(v) => value.push_str(&v),
+                    None => {
+                        return Err(InterpolationError::Undefined {
+                            key: key.to_string(),
+                            reference: name.clone(),
+                        });
+                    }
+                },
+                ValuePart::Reference(name) => {
+                    visit(name, templates, declared, resolved, lookup_env, stack)?;
+                    if let Some(v) = resolved.get(name) {
+                        value.push_str(v);
+                    } else if declared.contains(name) {
+                        return Err(InterpolationError::Unresolved {
+                            key: key.to_string(),
+                            reference: name.clone(),
+                        });
+                    } else if let Some(v) = lookup_env(name) {
+                        value.push_str(&v);
+                    } else {
+                        return Err(InterpolationError::Undefined {
+                            key: key.to_string(),
+                            reference: name.clone(),
+                        });
+                    }
+                }
+            }
+        }
+        stack.pop();
+
+        resolved.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    let mut stack = Vec::new();
+    for key in templates.keys() {
+        visit(key, templates, declared, resolved, &lookup_env, &mut stack)?;
+    }
+
+    Ok(())
+}
+
+/// Short environment names, extended or overridden by `ENVGG_ALIASES`.
+pub const DEFAULT_ENV_ALIASES: [(&str, &str); 3] =
+    [("d", "development"), ("s", "staging"), ("p", "production")];
+
+/// Short environment names mapped to full ones: the defaults, then aliases
+/// from `envgg.toml`, then `alias=name` pairs from the comma-separated
+/// `ENVGG_ALIASES` variable (e.g. `ENVGG_ALIASES=q=qa,pr=preview`).
+pub fn env_aliases(config: &config::Config) -> anyhow::Result<IndexMap<String, String>> {
+    let mut aliases: IndexMap<String, String> = DEFAULT_ENV_ALIASES
+        .into_iter()
+        .chain(config.aliases())
+        .map(|(alias, name)| (alias.to_string(), name.to_string()))
+        .collect();
+
+    if let Ok(extra) = std::env::var("ENVGG_ALIASES") {
+        for pair in extra.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (alias, name) = pair.split_once('=').with_context(|| {
+                format!(
+                    "Invalid ENVGG_ALIASES entry '{}', expected alias=name",
+                    pair
+                )
+            })?;
+            aliases.insert(alias.trim().to_string(), name.trim().to_string());
+        }
+    }
+
+    Ok(aliases)
+}
+
+/// The env file for an environment: `.env` when none is given, otherwise
+/// `.env.<name>`.
+pub fn env_file_path(env: Option<&str>) -> PathBuf {
+    match env {
+        None => PathBuf::from(".env"),
+        Some(name) => PathBuf::from(format!(".env.{}", name)),
+    }
+}
+
+/// The directories env files are loaded from, outermost first: `start` and
+/// its parents up to the project root, which is the nearest one containing
+/// `.git` or `envgg.toml`. Outside a project only `start` is searched.
+pub fn project_dirs(start: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    let mut relative = PathBuf::new();
+    for dir in std::path::absolute(start)?.ancestors() {
+        // Keep paths from the current directory relative, like `../.env`
+        dirs.push(match start == Path::new(".") {
+            true => relative.clone(),
+            false => start.join(&relative),
+        });
+        if dir.join(".git").exists() || dir.join(config::CONFIG_FILE_NAME).is_file() {
+            dirs.reverse();
+            return Ok(dirs);
+        }
+        relative.push("..");
+    }
+
+    dirs.truncate(1);
+    Ok(dirs)
+}
+
+/// The env files loaded for an environment that exist, lowest precedence
+/// first. Each of `dirs`, from the project root down, contributes `.env`,
+/// `.env.local`, then `.env.<env>` and `.env.<env>.local`. A file is loaded
+/// once even if it fills several of those roles, as for the `local` env.
+pub fn env_layers(dirs: &[PathBuf], env: Option<&str>) -> Vec<PathBuf> {
+    let mut names = IndexSet::from([PathBuf::from(".env"), PathBuf::from(".env.local")]);
+    if let Some(name) = env {
+        names.insert(env_file_path(Some(name)));
+        names.insert(PathBuf::from(format!(".env.{}.local", name)));
+    }
+
+    dirs.iter()
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// Every `.env` and `.env.*` file in `dir`, with `.env` first and the rest
+/// sorted by name.
+pub fn list_env_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            (name == ".env" || name.starts_with(".env.")) && entry.path().is_file()
+        })
+        .map(|entry| match dir == Path::new(".") {
+            // Keep paths in the current directory relative, like `.env`
+            true => PathBuf::from(entry.file_name()),
+            false => entry.path(),
+        })
+        .collect();
+
+    files.sort_by_key(|path| (path.file_name() != Some(".env".as_ref()), path.clone()));
+
+    Ok(files)
+}
+
+/// Where a variable was defined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvSource {
+    pub path: PathBuf,
+    pub line: usize,
+}
+
+impl fmt::Display for EnvSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.path.display(), self.line)
+    }
+}
+
+pub fn get_env_var_names_from_file(path: &PathBuf) -> anyhow::Result<IndexSet<String>> {
+    Ok(get_env_var_sources_from_file(path)?.into_keys().collect())
+}
+
+/// Every variable defined by an env file (including its includes), mapped to
+/// the file and line of the definition that takes effect.
+pub fn get_env_var_sources_from_file(
+    path: &PathBuf,
+) -> anyhow::Result<IndexMap<String, EnvSource>> {
+    let mut sources = IndexMap::new();
+
+    for (line, source) in try_read_env_file_with_sources(path)? {
+        if let Some(key) = line.key() {
+            sources.shift_remove(key);
+            sources.insert(key.to_string(), source);
+        }
+    }
+
+    Ok(sources)
+}
+
+/// Reads an env file, splicing in `#@include` files where they appear so
+/// that later definitions override earlier ones.
+pub fn read_env_file(path: &PathBuf) -> anyhow::Result<Vec<EnvLine>> {
+    let lines = read_env_entries(path, false, &mut Vec::new())?;
+    Ok(lines.into_iter().map(|(line, _)| line).collect())
+}
+
+/// Like [`read_env_file`], but fails with [`EnvFileErrors`] listing every
+/// malformed line instead of silently turning it into a keyring lookup.
+pub fn try_read_env_file(path: &PathBuf) -> anyhow::Result<Vec<EnvLine>> {
+    let lines = try_read_env_file_with_sources(path)?;
+    Ok(lines.into_iter().map(|(line, _)| line).collect())
+}
+
+/// Like [`try_read_env_file`], but also reports where each line came from.
+pub fn try_read_env_file_with_sources(path: &PathBuf) -> anyhow::Result<Vec<(EnvLine, EnvSource)>> {
+    read_env_entries(path, true, &mut Vec::new())
+}
+
+/// `stack` holds the canonical paths of the files currently being read, to
+/// detect include cycles.
+fn read_env_entries(
+    path: &PathBuf,
+    strict: bool,
+    stack: &mut Vec<PathBuf>,
+) -> anyhow::Result<Vec<(EnvLine, EnvSource)>> {
+    let canonical =
+        fs::canonicalize(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    if let Some(pos) = stack.iter().position(|p| *p == canonical) {
+        let cycle: Vec<String> = stack[pos..]
+            .iter()
+            .chain([&canonical])
+            .map(|p| p.display().to_string())
+            .collect();
+        anyhow::bail!("include cycle: {}", cycle.join(" -> "));
+    }
+
+    let content = read_env_file_contents(path)?;
+
+    let mut parsed = Vec::new();
+    let mut errors = Vec::new();
+
+    for (start, entry) in split_entries(&content) {
+        let source = EnvSource {
+            path: path.clone(),
+            line: content[..start].matches('\n').count() + 1,
+        };
+
+        if !strict {
+            parsed.push((parse_env_line(entry), source));
+            continue;
+        }
+
+        match try_parse_entry(entry) {
+            Ok(env_line) => parsed.push((env_line, source)),
+            Err((offset, reason)) => {
+                let offset = start + offset;
+                let line_start = content[..offset].rfind('\n').map_or(0, |i| i + 1);
+                let line_end = content[offset..]
+                    .find('\n')
+                    .map_or(content.len(), |i| offset + i);
+                errors.push(EnvParseError {
+                    path: path.clone(),
+                    line: content[..offset].matches('\n').count() + 1,
+                    column: line_col(&content[line_start..], offset - line_start),
+                    reason,
+                    source_line: content[line_start..line_end].to_string(),
+                });
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(EnvFileErrors(errors).into());
+    }
+
+    stack.push(canonical);
+    let mut lines = Vec::with_capacity(parsed.len());
+    for (line, source) in parsed {
+        match line {
+            EnvLine::Include { path: include } => {
+                let include_path = path.parent().unwrap_or(Path::new("")).join(include);
+                let included = read_env_entries(&include_path, strict, stack)
+                    .with_context(|| format!("included from {}", source))?;
+                lines.extend(included);
+            }
+            line => lines.push((line, source)),
+        }
+    }
+    stack.pop();
+
+    Ok(lines)
+}
+
+/// Every file `path` includes, directly or through other includes, whether
+/// or not it exists. Unreadable files are skipped, as are include cycles.
+pub fn included_files(path: &Path) -> Vec<PathBuf> {
+    let mut files = IndexSet::new();
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let Ok(content) = read_env_file_contents(&path) else {
+            continue;
+        };
+        for (_, entry) in split_entries(&content) {
+            if let EnvLine::Include { path: include } = parse_env_line(entry) {
+                let include = path.parent().unwrap_or(Path::new("")).join(include);
+                if files.insert(include.clone()) {
+                    pending.push(include);
+                }
+            }
+        }
+    }
+    files.into_iter().collect()
+}
+
+/// Format a value so that it parses back to exactly `value`, quoting only
+/// when needed.
+pub fn quote_value(value: &str) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "-_./:@,+%^~=".contains(c);
+
+    if value.chars().all(is_plain) {
+        value.to_string()
+    } else if !value.contains(['\'', '\n', '\r']) {
+        format!("'{}'", value)
+    } else {
+        format!("\"{}\"", escape_double_quoted(value))
+    }
+}
+
+/// Mask a secret for display, keeping only enough to recognise it: the
+/// first four and last three characters of values of 12 or more characters,
+/// e.g. `sk_l****9f2`, and nothing of shorter ones.
+pub fn mask_value(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    if chars.len() < 12 {
+        return "****".to_string();
+    }
+
+    let head: String = chars[..4].iter().collect();
+    let tail: String = chars[chars.len() - 3..].iter().collect();
+    format!("{}****{}", head, tail)
+}
+
+/// Escape text for use between double quotes in an env file.
+fn escape_double_quoted(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '$' => escaped.push_str("\\$"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Format an [`EnvLine`] as it would appear in an env file (without a line
+/// terminator). Comments format as an empty line.
+pub fn format_env_line(line: &EnvLine) -> String {
+    match line {
+        EnvLine::Comment => String::new(),
+        EnvLine::Include { path } => format!("#@include {}", path),
+        EnvLine::Lookup { key, presence } => format!("{}{}", key, presence.marker()),
+        EnvLine::Alias {
+            key,
+            keyring_key,
+            presence,
+            default: None,
+        } => format!("{}{}=${}", key, presence.marker(), keyring_key),
+        EnvLine::Alias {
+            key,
+            keyring_key,
+            presence,
+            default: Some(default),
+        } => {
+            let default = quote_value(default);
+            format!(
+                "{}{}=${{{}:-{}}}",
+                key,
+                presence.marker(),
+                keyring_key,
+                default
+            )
+        }
+        EnvLine::Direct { key, value } => format!("{}={}", key, quote_value(value)),
+        EnvLine::Interpolated { key, parts } => {
+            let value: String = parts
+                .iter()
+                .map(|part| match part {
+                    ValuePart::Literal(text) => escape_double_quoted(text),
+                    ValuePart::Reference(name) => format!("${{{}}}", name),
+                })
+                .collect();
+            format!("{}=\"{}\"", key, value)
+        }
+    }
+}
+
+/// One entry of an [`EnvDocument`]: the exact source text, including its
+/// line terminator, and how it parses.
+#[derive(Debug, Clone)]
+pub struct EnvEntry {
+    raw: String,
+    line: EnvLine,
+}
+
+impl EnvEntry {
+    fn new(raw: String) -> Self {
+        let body = raw.trim_end_matches(['\r', '\n']).replace("\r\n", "\n");
+        let line = parse_env_line(&body);
+        Self { raw, line }
+    }
+
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    pub fn line(&self) -> &EnvLine {
+        &self.line
+    }
+
+    pub fn key(&self) -> Option<&str> {
+        self.line.key()
+    }
+
+    fn terminator(&self) -> &str {
+        let body = self.raw.trim_end_matches(['\r', '\n']);
+        &self.raw[body.len()..]
+    }
+}
+
+/// A lossless, editable view of an env file.
+///
+/// Untouched entries are written back byte for byte, including comments,
+/// blank lines, quoting style and line endings. Edited entries keep their
+/// indentation, `export` prefix, trailing comment and line ending.
+#[derive(Debug, Clone)]
+pub struct EnvDocument {
+    entries: Vec<EnvEntry>,
+    line_ending: &'static str,
+}
+
+impl EnvDocument {
+    pub fn parse(content: &str) -> Self {
+        let line_ending = if content.contains("\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        let entries = split_raw_entries(content)
+            .into_iter()
+            .map(|raw| EnvEntry::new(raw.to_string()))
+            .collect();
+
+        Self {
+            entries,
+            line_ending,
+        }
+    }
+
+    pub fn read(path: &PathBuf) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        Ok(Self::parse(&content))
+    }
+
+    /// Write the document back, replacing `path` atomically and keeping its
+    /// permissions. A symlinked env file is written at the link's target.
+    pub fn write(&self, path: &PathBuf) -> anyhow::Result<()> {
+        use std::io::Write;
+
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+        let file_name = path
+            .file_name()
+            .and_then(|f| f.to_str())
+            .context("invalid env file path")?;
+        let tmp = path.with_file_name(format!(".{}.envgg-tmp", file_name));
+        let permissions = fs::metadata(&path).map(|metadata| metadata.permissions());
+
+        // Left behind by a write that was interrupted
+        _ = fs::remove_file(&tmp);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        // Never more open than the file it replaces, even for a moment
+        #[cfg(unix)]
+        if let Ok(permissions) = &permissions {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(permissions.mode() & 0o777);
+        }
+        let mut file = options
+            .open(&tmp)
+            .with_context(|| format!("Failed to create {}", tmp.display()))?;
+
+        let result = (|| {
+            file.write_all(self.to_string().as_bytes())
+                .with_context(|| format!("Failed to write {}", tmp.display()))?;
+            if let Ok(permissions) = permissions {
+                file.set_permissions(permissions)?;
+            }
+            drop(file);
+            fs::rename(&tmp, &path).with_context(|| format!("Failed to replace {}", path.display()))
+        })();
+        if result.is_err() {
+            _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    pub fn entries(&self) -> &[EnvEntry] {
+        &self.entries
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = &EnvLine> {
+        self.entries.iter().map(EnvEntry::line)
+    }
+
+    /// Index of the last (effective) definition of `key`.
+    pub fn position(&self, key: &str) -> Option<usize> {
+        self.entries.iter().rposition(|e| e.key() == Some(key))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&EnvLine> {
+        self.position(key).map(|i| self.entries[i].line())
+    }
+
+    /// Replace the effective definition of the line's key in place, or
+    /// append it if the key is not defined yet.
+    pub fn set(&mut self, line: EnvLine) {
+        let Some(key) = line.key() else {
+            return;
+        };
+
+        match self.position(key) {
+            Some(i) => self.replace(i, line),
+            None => self.insert(self.entries.len(), line),
+        }
+    }
+
+    /// Point every line sourced from the keyring key `from` at `to` instead,
+    /// keeping the variable names: `KEY=$FROM` becomes `KEY=$TO`, and a bare
+    /// `FROM` becomes `FROM=$TO`. Returns how many lines changed.
+    pub fn rename_keyring_key(&mut self, from: &str, to: &str) -> usize {
+        let mut renamed = 0;
+        for i in 0..self.entries.len() {
+            let line = match self.entries[i].line.clone() {
+                EnvLine::Lookup { key, presence } if key == from => EnvLine::Alias {
+                    key,
+                    keyring_key: to.to_string(),
+                    presence,
+                    default: None,
+                },
+                EnvLine::Alias {
+                    key,
+                    keyring_key,
+                    presence,
+                    default,
+                } if keyring_key == from => EnvLine::Alias {
+                    key,
+                    keyring_key: to.to_string(),
+                    presence,
+                    default,
+                },
+                _ => continue,
+            };
+            self.replace(i, line);
+            renamed += 1;
+        }
+        renamed
+    }
+
+    /// Replace the entry at `index`, keeping its indentation, `export` prefix,
+    /// trailing comment and line ending.
+    fn replace(&mut self, index: usize, line: EnvLine) {
+        let entry = &self.entries[index];
+        let body = entry.raw.trim_end_matches(['\r', '\n']);
+        // Keep indentation and any `export` prefix
+        let prefix = &body[..offset_in(body, strip_export(body.trim_start()))];
+        let comment = match (&entry.line, &line) {
+            (EnvLine::Lookup { .. }, _) | (_, EnvLine::Lookup { .. }) => None,
+            _ => inline_comment(body),
+        };
+
+        let mut raw = format!("{}{}", prefix, format_env_line(&line));
+        if let Some(comment) = comment {
+            raw.push(' ');
+            raw.push_str(comment);
+        }
+        raw.push_str(entry.terminator());
+
+        self.entries[index] = EnvEntry { raw, line };
+    }
 
-// TODO: should error for malformed entries
-pub fn parse_env_line(line: &str) -> EnvLine {
-    let trimmed = line.trim();
+    /// Insert a new entry before index `index`.
+    pub fn insert(&mut self, index: usize, line: EnvLine) {
+        self.insert_raw(index, format_env_line(&line));
+    }
 
-    // Skip empty lines and comments (lines starting with #)
-    if trimmed.is_empty() || trimmed.starts_with('#') {
-        return EnvLine::Comment;
+    /// Insert a comment (the text is written verbatim after `# `).
+    pub fn insert_comment(&mut self, index: usize, text: &str) {
+        self.insert_raw(index, format!("# {}", text));
     }
 
-    // Check for KEY=VALUE format
-    if let Some(pos) = trimmed.find('=') {
-        let key = trimmed[..pos].trim().to_string();
-        let value = trimmed[pos + 1..].trim().to_string();
+    fn insert_raw(&mut self, index: usize, body: String) {
+        // A file without a trailing newline needs one before anything is added
+        if index == self.entries.len()
+            && let Some(last) = self.entries.last_mut()
+            && last.terminator().is_empty()
+        {
+            last.raw.push_str(self.line_ending);
+        }
 
-        // Case: KEY=$OTHER - alias for keyring lookup
-        if let Some(val) = value.strip_prefix('$') {
-            let keyring_key = val.trim().to_string();
-            EnvLine::Alias { key, keyring_key }
-        } else {
-            // Case: KEY=value - direct value assignment
-            // Remove quotes if present
-            let value = if (value.starts_with('"') && value.ends_with('"'))
-                || (value.starts_with('\'') && value.ends_with('\''))
-            {
-                value[1..value.len() - 1].to_string()
-            } else {
-                value
-            };
+        let raw = format!("{}{}", body, self.line_ending);
+        self.entries.insert(index, EnvEntry::new(raw));
+    }
 
-            EnvLine::Direct { key, value }
+    /// Remove every definition of `key`, returning whether any existed.
+    pub fn remove(&mut self, key: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|e| e.key() != Some(key));
+        self.entries.len() != before
+    }
+
+    /// Remove the entry at `index`.
+    pub fn remove_entry(&mut self, index: usize) {
+        self.entries.remove(index);
+    }
+
+    /// Move the entry at `from` so that it ends up at index `to`.
+    pub fn move_entry(&mut self, from: usize, to: usize) {
+        let entry = self.entries.remove(from);
+        self.entries.insert(to, entry);
+        // Moving the unterminated last line must not glue it to its neighbour
+        let line_ending = self.line_ending;
+        let last = self.entries.len() - 1;
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            if i != last && entry.terminator().is_empty() {
+                entry.raw.push_str(line_ending);
+            }
         }
-    } else {
-        // Case: KEY only (no =) - lookup from keyring
-        let key = trimmed.to_string();
-        EnvLine::Lookup { key }
     }
 }
 
-pub fn get_env_var_names_from_file(path: &PathBuf) -> anyhow::Result<IndexSet<String>> {
-    let lines = read_env_file(path)?;
+impl fmt::Display for EnvDocument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.entries.iter().try_for_each(|e| f.write_str(&e.raw))
+    }
+}
 
-    let var_names: IndexSet<String> = lines
-        .into_iter()
-        .filter_map(|line| match line {
-            EnvLine::Comment => None,
-            EnvLine::Alias { key, .. } => Some(key),
-            EnvLine::Direct { key, .. } => Some(key),
-            EnvLine::Lookup { key } => Some(key),
-        })
-        .collect();
+/// Env files with every reference to one keyring key pointed at another,
+/// edited in memory so that nothing is written unless every file was read.
+pub struct ReferenceRewrite {
+    changes: Vec<(PathBuf, EnvDocument, usize)>,
+}
+
+impl ReferenceRewrite {
+    /// Read `files` and point their references to `from` at `to`, failing
+    /// with every file that could not be read.
+    pub fn new(files: &[PathBuf], from: &str, to: &str) -> anyhow::Result<Self> {
+        let mut changes = Vec::new();
+        let mut errors = Vec::new();
+        for path in files {
+            match EnvDocument::read(path) {
+                Ok(mut doc) => {
+                    let renamed = doc.rename_keyring_key(from, to);
+                    if renamed > 0 {
+                        changes.push((path.clone(), doc, renamed));
+                    }
+                }
+                Err(e) => errors.push(format!("{:#}", e)),
+            }
+        }
+        if !errors.is_empty() {
+            anyhow::bail!("{}", errors.join("\n"));
+        }
+        Ok(Self { changes })
+    }
 
-    Ok(var_names)
+    /// Write every changed file, returning how many lines changed in each.
+    /// A file that fails to write does not stop the others; the error lists
+    /// every failure.
+    pub fn write(self) -> anyhow::Result<Vec<(PathBuf, usize)>> {
+        let mut written = Vec::new();
+        let mut errors = Vec::new();
+        for (path, doc, renamed) in self.changes {
+            match doc.write(&path) {
+                Ok(()) => written.push((path, renamed)),
+                Err(e) => errors.push(format!("{:#}", e)),
+            }
+        }
+        if !errors.is_empty() {
+            anyhow::bail!("{}", errors.join("\n"));
+        }
+        Ok(written)
+    }
 }
 
-pub fn read_env_file(path: &PathBuf) -> anyhow::Result<Vec<EnvLine>> {
-    let file = fs::File::open(path)?;
-    let reader = io::BufReader::new(file);
-    let lines: Vec<String> = reader.lines().collect::<Result<_, _>>()?;
+/// The `# comment` after a single-line entry's value, if any.
+fn inline_comment(body: &str) -> Option<&str> {
+    let eq = body.find('=')?;
+    let raw = &body[eq + 1..];
+    let value = raw.trim_start();
+
+    let rest = match value.chars().next() {
+        Some('\'') => &value[value[1..].find('\'')? + 2..],
+        Some('"') => &value[scan_parts(value, 1, Some('"')).ok()?.1? + 1..],
+        _ => &raw[strip_inline_comment(raw).len()..],
+    };
 
-    let var_names = lines.iter().map(|line| parse_env_line(line)).collect();
+    let rest = rest.trim();
+    rest.starts_with('#').then_some(rest)
+}
+
+/// Use `namespace` as the keyring service instead of `envgg`. Only the first
+/// call has an effect, and it must happen before any keyring access.
+pub fn set_keyring_namespace(namespace: &str) {
+    _ = NAMESPACE.set(namespace.to_string());
+}
 
-    Ok(var_names)
+/// The keyring service that secrets are stored under.
+pub fn keyring_namespace() -> &'static str {
+    NAMESPACE.get().map_or(TAG, String::as_str)
 }
 
 pub fn add_secret_to_keyring(key: &str, value: &str) -> anyhow::Result<()> {
-    let entry = keyring_core::Entry::new(TAG, key)?;
+    let entry = keyring_core::Entry::new(keyring_namespace(), key)?;
     entry.set_password(value)?;
+    forget_cached_secret(key);
     Ok(())
 }
 
 pub fn delete_secret_from_keyring(key: &str) -> anyhow::Result<()> {
-    let entry = keyring_core::Entry::new(TAG, key)?;
+    let entry = keyring_core::Entry::new(keyring_namespace(), key)?;
     entry.delete_credential()?;
+    forget_cached_secret(key);
     Ok(())
 }
 
+/// Stop a running `envgg agent` from serving the old value of `key`. Best
+/// effort: the keyring has already changed, and the cached copy expires
+/// with its TTL anyway.
+fn forget_cached_secret(key: &str) {
+    #[cfg(unix)]
+    let _ = agent::forget(&agent::socket_path(), keyring_namespace(), key);
+    #[cfg(not(unix))]
+    let _ = key;
+}
+
 pub fn list_secret_labels() -> anyhow::Result<Vec<String>> {
-    let search_params = HashMap::from([("service", TAG)]);
+    let search_params = HashMap::from([("service", keyring_namespace())]);
 
     let items = keyring_core::Entry::search(&search_params)?;
 
@@ -114,7 +1507,1302 @@ pub fn list_secret_labels() -> anyhow::Result<Vec<String>> {
 }
 
 pub fn get_secret_from_keyring(target: &str) -> anyhow::Result<String> {
-    let entry = keyring_core::Entry::new(TAG, target)?;
+    let entry = keyring_core::Entry::new(keyring_namespace(), target)?;
     let password = entry.get_password()?;
     Ok(password)
 }
+
+/// The secret stored under `key`, or `None` if the keyring has no such entry.
+pub fn find_secret_in_keyring(key: &str) -> anyhow::Result<Option<String>> {
+    match get_secret_from_keyring(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if matches!(e.downcast_ref(), Some(keyring_core::Error::NoEntry)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether the keyring has a secret stored under `key`.
+pub fn secret_exists(key: &str) -> anyhow::Result<bool> {
+    Ok(find_secret_in_keyring(key)?.is_some())
+}
+
+/// Copy the secret `from` to `to`, replacing any secret already stored under
+/// `to`. Returns the value it replaced.
+pub fn copy_secret(from: &str, to: &str) -> anyhow::Result<Option<String>> {
+    if from == to {
+        anyhow::bail!("{} cannot be copied onto itself", from);
+    }
+    let value = get_secret_from_keyring(from)
+        .with_context(|| format!("Failed to read {} from the keyring", from))?;
+    let previous = find_secret_in_keyring(to)?;
+    add_secret_to_keyring(to, &value)
+        .with_context(|| format!("Failed to store {} in the keyring", to))?;
+    Ok(previous)
+}
+
+/// Rename the secret `from` to `to`, replacing any secret already stored
+/// under `to`. If `from` can't be deleted once copied, `to` is put back the
+/// way it was, so the keyring is never left holding both.
+pub fn rename_secret(from: &str, to: &str) -> anyhow::Result<()> {
+    let previous = copy_secret(from, to)?;
+    let Err(e) = delete_secret_from_keyring(from) else {
+        return Ok(());
+    };
+
+    let rollback = match &previous {
+        Some(value) => add_secret_to_keyring(to, value),
+        None => delete_secret_from_keyring(to),
+    };
+    Err(match rollback {
+        Ok(()) => e.context(format!(
+            "Failed to remove {} after copying it to {}; the copy was undone",
+            from, to
+        )),
+        Err(rollback) => e.context(format!(
+            "Failed to remove {} after copying it to {}, and undoing the copy failed too: {:#}",
+            from, to, rollback
+        )),
+    })
+}
+
+/// The result of one keyring lookup and how long it took.
+pub struct KeyringLookup {
+    pub value: anyhow::Result<String>,
+    pub elapsed: Duration,
+}
+
+/// Look up several secrets at once, each key only once, with up to
+/// `KEYRING_CONCURRENCY` blocking keyring calls in flight. Results are in the
+/// order the keys were first given.
+pub async fn get_secrets_from_keyring(
+    keys: impl IntoIterator<Item = String>,
+) -> IndexMap<String, KeyringLookup> {
+    use futures::stream::{self, StreamExt};
+
+    let mut keys = keys.into_iter().collect::<IndexSet<String>>().into_iter();
+    let mut secrets = IndexMap::new();
+
+    // The first lookup may prompt to unlock the keyring, so it runs alone
+    // rather than racing the others into several prompts
+    let Some(first) = keys.next() else {
+        return secrets;
+    };
+    secrets.insert(first.clone(), lookup_secret(first).await);
+
+    let rest = stream::iter(keys)
+        .map(|key| async move {
+            let lookup = lookup_secret(key.clone()).await;
+            (key, lookup)
+        })
+        .buffered(KEYRING_CONCURRENCY);
+    secrets.extend(rest.collect::<Vec<_>>().await);
+    secrets
+}
+
+async fn lookup_secret(key: String) -> KeyringLookup {
+    tokio::task::spawn_blocking(move || {
+        let start = Instant::now();
+        let value = get_secret_from_keyring(&key);
+        KeyringLookup {
+            value,
+            elapsed: start.elapsed(),
+        }
+    })
+    .await
+    .unwrap_or_else(|e| KeyringLookup {
+        value: Err(e.into()),
+        elapsed: Duration::ZERO,
+    })
+}
+
+/// A key that could not be resolved, and where it was defined (nowhere for
+/// a key that envgg.toml requires but no env file defines).
+pub struct MissingSecret {
+    pub key: String,
+    pub keyring_key: String,
+    pub presence: Presence,
+    pub error: String,
+    pub source: Option<EnvSource>,
+}
+
+/// A variable ready to pass to the command, with the file and line (and so
+/// the layer) that supplied it.
+pub struct ResolvedVar {
+    pub key: String,
+    pub value: String,
+    pub source: EnvSource,
+    pub origin: Origin,
+}
+
+/// How a variable got its value.
+pub enum Origin {
+    Direct,
+    Interpolated,
+    Keyring,
+    Alias {
+        keyring_key: String,
+    },
+    /// `${ALIAS:-default}` whose keyring key was missing
+    AliasDefault {
+        keyring_key: String,
+    },
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Direct => write!(f, "direct"),
+            Origin::Interpolated => write!(f, "interpolated"),
+            Origin::Keyring => write!(f, "keyring"),
+            Origin::Alias { keyring_key } => write!(f, "alias → {}", keyring_key),
+            Origin::AliasDefault { keyring_key } => {
+                write!(f, "default ({} not in keyring)", keyring_key)
+            }
+        }
+    }
+}
+
+/// Everything resolved from the env files, and everything that was not.
+/// Callers decide which failures are fatal.
+pub struct Resolution {
+    pub vars: Vec<ResolvedVar>,
+    pub missing: Vec<MissingSecret>,
+    pub interpolation_error: Option<InterpolationError>,
+}
+
+impl MissingSecret {
+    /// Whether this key stops a command from running: always when it is
+    /// required, and with `strict` unless it is optional.
+    pub fn is_fatal(&self, strict: bool) -> bool {
+        match self.presence {
+            Presence::Required => true,
+            Presence::Default => strict,
+            Presence::Optional => false,
+        }
+    }
+}
+
+impl Resolution {
+    /// The variables to run a command with, or an error naming every fatal
+    /// missing secret at once, or the interpolation error.
+    pub fn into_vars(self, strict: bool) -> anyhow::Result<Vec<ResolvedVar>> {
+        let fatal: Vec<_> = self.missing.iter().filter(|m| m.is_fatal(strict)).collect();
+        if !fatal.is_empty() {
+            let mut message = format!(
+                "{} required secret(s) missing from the keyring:",
+                fatal.len()
+            );
+            for missing in fatal {
+                if missing.key == missing.keyring_key {
+                    message.push_str(&format!("\n  {}: {}", missing.key, missing.error));
+                } else {
+                    message.push_str(&format!(
+                        "\n  {} (keyring key '{}'): {}",
+                        missing.key, missing.keyring_key, missing.error
+                    ));
+                }
+            }
+            anyhow::bail!(message);
+        }
+
+        if let Some(e) = self.interpolation_error {
+            return Err(e.into());
+        }
+        Ok(self.vars)
+    }
+}
+
+/// Replace each reference to `key` in `parts` with the definition `below` that
+/// `parts` overrides. A secret's value isn't known yet, so it is referenced as
+/// `below_name` instead.
+fn extend_definition(
+    key: &str,
+    parts: Vec<ValuePart>,
+    below: &EnvLine,
+    below_name: &str,
+) -> Vec<ValuePart> {
+    let mut extended = Vec::new();
+    for part in parts {
+        if !matches!(&part, ValuePart::Reference(name) if name == key) {
+            extended.push(part);
+            continue;
+        }
+        match below {
+            EnvLine::Direct { value, .. } => extended.push(ValuePart::Literal(value.clone())),
+            // Its own self-references, if any, still mean the parent environment
+            EnvLine::Interpolated { parts, .. } => extended.extend(parts.iter().cloned()),
+            _ => extended.push(ValuePart::Reference(below_name.to_string())),
+        }
+    }
+    extended
+}
+
+/// Read and resolve the env files in `paths`, in order; if duplicate labels
+/// exist, the last entry takes precedence. Keys in `required` must resolve as
+/// if marked `KEY!`, and must be defined somewhere. With `no_override`, keys
+/// already set in the parent environment are left out. `parent` limits the
+/// parent environment to the given variables, as `--pure` does; otherwise it
+/// is envgg's own.
+pub async fn process_env_file(
+    paths: &[PathBuf],
+    required: &[String],
+    no_override: bool,
+    parent: Option<&[(String, String)]>,
+    verbose: bool,
+) -> anyhow::Result<Resolution> {
+    let parent_var = |name: &str| match parent {
+        Some(vars) => vars
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone()),
+        None => std::env::var(name).ok(),
+    };
+
+    let mut lines = Vec::new();
+    for path in paths {
+        lines.extend(try_read_env_file_with_sources(path)?);
+    }
+
+    // Keep only the last definition of each key, in file order. A value that
+    // references its own key, like `PATH="${PATH}:./bin"`, extends the
+    // definition it overrides, or the parent environment if there is none.
+    let mut definitions: IndexMap<String, EnvLine> = IndexMap::new();
+    let mut sources: IndexMap<String, EnvSource> = IndexMap::new();
+    // Overridden secrets that a later value extends, under a name of their
+    // own (which no key can have), mapped to their key
+    let mut overridden: IndexMap<String, String> = IndexMap::new();
+    for (line, source) in lines {
+        let Some(key) = line.key().map(str::to_string) else {
+            continue;
+        };
+        let line = match (line, definitions.shift_remove(&key)) {
+            (EnvLine::Interpolated { key, parts }, Some(below))
+                if parts.contains(&ValuePart::Reference(key.clone())) =>
+            {
+                let name = format!("{} ({})", key, sources[&key]);
+                let parts = extend_definition(&key, parts, &below, &name);
+                let secret = match below {
+                    EnvLine::Lookup { key, presence } => Some((key, presence, None)),
+                    EnvLine::Alias {
+                        keyring_key,
+                        presence,
+                        default,
+                        ..
+                    } => Some((keyring_key, presence, default)),
+                    _ => None,
+                };
+                if let Some((keyring_key, presence, default)) = secret {
+                    let secret = EnvLine::Alias {
+                        key: name.clone(),
+                        keyring_key,
+                        presence,
+                        default,
+                    };
+                    definitions.insert(name.clone(), secret);
+                    sources.insert(name.clone(), sources[&key].clone());
+                    overridden.insert(name, key.clone());
+                }
+                EnvLine::Interpolated { key, parts }
+            }
+            (line, _) => line,
+        };
+        definitions.insert(key.clone(), line);
+        sources.insert(key, source);
+    }
+
+    let mut missing = Vec::new();
+    for key in required {
+        match definitions.get_mut(key) {
+            Some(EnvLine::Lookup { presence, .. } | EnvLine::Alias { presence, .. }) => {
+                *presence = Presence::Required;
+            }
+            Some(_) => {}
+            None => missing.push(MissingSecret {
+                key: key.clone(),
+                keyring_key: key.clone(),
+                presence: Presence::Required,
+                error: "not defined in any env file".to_string(),
+                source: None,
+            }),
+        }
+    }
+
+    // The child inherits these, and references to them fall back to the
+    // parent environment, so they are never looked up in the keyring
+    if no_override {
+        definitions.retain(|key, _| {
+            let key = overridden.get(key).unwrap_or(key);
+            parent_var(key).is_none()
+        });
+    }
+
+    let declared: IndexSet<String> = definitions.keys().cloned().collect();
+
+    // Interpolated values can reference any other key, so they are set
+    // aside and resolved last, in dependency order
+    let mut templates = IndexMap::new();
+    let lines: Vec<EnvLine> = definitions
+        .into_values()
+        .filter_map(|line| match line {
+            EnvLine::Interpolated { key, parts } => {
+                templates.insert(key, parts);
+                None
+            }
+            line => Some(line),
+        })
+        .collect();
+
+    // Fetch every referenced secret up front, concurrently and once per key
+    let keyring_keys = lines.iter().filter_map(|line| match line {
+        EnvLine::Alias { keyring_key, .. } => Some(keyring_key.clone()),
+        EnvLine::Lookup { key, .. } => Some(key.clone()),
+        _ => None,
+    });
+    let keyring_keys: IndexSet<String> = keyring_keys.collect();
+    let mut values: IndexMap<String, anyhow::Result<String>> = IndexMap::new();
+
+    // A running `envgg agent` answers first; the keyring supplies the rest
+    #[cfg(unix)]
+    let mut agent = agent::AgentClient::connect(&agent::socket_path())
+        .await
+        .unwrap_or(None);
+    #[cfg(unix)]
+    if let Some(agent) = &mut agent {
+        for key in &keyring_keys {
+            if let Ok(Some(value)) = agent.get(keyring_namespace(), key).await {
+                values.insert(key.clone(), Ok(value));
+            }
+        }
+        if verbose {
+            eprintln!("envgg: {} secret(s) from the agent", values.len());
+        }
+    }
+
+    let start = Instant::now();
+    let uncached = keyring_keys
+        .into_iter()
+        .filter(|key| !values.contains_key(key));
+    let secrets = get_secrets_from_keyring(uncached.collect::<Vec<_>>()).await;
+    if verbose && !secrets.is_empty() {
+        for (key, lookup) in &secrets {
+            eprintln!("envgg: keyring {} in {:.1?}", key, lookup.elapsed);
+        }
+        eprintln!(
+            "envgg: fetched {} secret(s) from the keyring in {:.1?}",
+            secrets.len(),
+            start.elapsed()
+        );
+    }
+
+    #[cfg(unix)]
+    if let Some(agent) = &mut agent {
+        for (key, lookup) in &secrets {
+            if let Ok(value) = &lookup.value {
+                _ = agent.put(keyring_namespace(), key, value).await;
+            }
+        }
+    }
+    for (key, lookup) in secrets {
+        values.insert(key, lookup.value);
+    }
+
+    let mut env_map: IndexMap<String, String> = IndexMap::new();
+    let mut origins: IndexMap<String, Origin> = IndexMap::new();
+
+    for line in lines {
+        let (key, keyring_key, presence, default) = match line {
+            EnvLine::Comment | EnvLine::Include { .. } | EnvLine::Interpolated { .. } => continue,
+            EnvLine::Direct { key, value } => {
+                env_map.insert(key.clone(), value);
+                origins.insert(key, Origin::Direct);
+                continue;
+            }
+            EnvLine::Alias {
+                key,
+                keyring_key,
+                presence,
+                default,
+            } => (key, keyring_key, presence, default),
+            EnvLine::Lookup { key, presence } => (key.clone(), key, presence, None),
+        };
+
+        let (value, origin) = match (&values[&keyring_key], default) {
+            (Ok(value), _) if key == keyring_key => (value.clone(), Origin::Keyring),
+            (Ok(value), _) => (value.clone(), Origin::Alias { keyring_key }),
+            // `${ALIAS:-default}` falls back when the secret does not exist,
+            // but keyring failures are still reported
+            (Err(error), Some(default))
+                if matches!(error.downcast_ref(), Some(keyring_core::Error::NoEntry)) =>
+            {
+                (default, Origin::AliasDefault { keyring_key })
+            }
+            (Err(error), _) => {
+                missing.push(MissingSecret {
+                    source: Some(sources[&key].clone()),
+                    key,
+                    keyring_key,
+                    presence,
+                    error: error.to_string(),
+                });
+                continue;
+            }
+        };
+        env_map.insert(key.clone(), value);
+        origins.insert(key, origin);
+    }
+
+    // A missing `KEY?` interpolates as empty rather than leaving its
+    // references unresolved
+    let skipped: IndexSet<&str> = missing
+        .iter()
+        .filter(|m| m.presence == Presence::Optional)
+        .map(|m| m.key.as_str())
+        .collect();
+    let declared: IndexSet<String> = declared
+        .into_iter()
+        .filter(|key| !skipped.contains(key.as_str()))
+        .collect();
+    let interpolation_error = interpolate(&templates, &declared, &mut env_map, |name| {
+        if skipped.contains(name) {
+            return Some(String::new());
+        }
+        parent_var(name)
+    })
+    .err();
+
+    // Overridden secrets are reported under their key, at their own line
+    for missing in &mut missing {
+        if let Some(key) = overridden.get(&missing.key) {
+            missing.key = key.clone();
+        }
+    }
+    let vars = env_map
+        .into_iter()
+        .filter(|(key, _)| !overridden.contains_key(key))
+        .map(|(key, value)| ResolvedVar {
+            source: sources[&key].clone(),
+            origin: origins.shift_remove(&key).unwrap_or(Origin::Interpolated),
+            key,
+            value,
+        })
+        .collect();
+
+    Ok(Resolution {
+        vars,
+        missing,
+        interpolation_error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn direct(key: &str, value: &str) -> EnvLine {
+        EnvLine::Direct {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn alias(key: &str, keyring_key: &str) -> EnvLine {
+        EnvLine::Alias {
+            key: key.to_string(),
+            keyring_key: keyring_key.to_string(),
+            presence: Presence::Default,
+            default: None,
+        }
+    }
+
+    fn lookup(key: &str, presence: Presence) -> EnvLine {
+        EnvLine::Lookup {
+            key: key.to_string(),
+            presence,
+        }
+    }
+
+    fn interpolated(key: &str, parts: &[(&str, bool)]) -> EnvLine {
+        let parts = parts
+            .iter()
+            .map(|&(text, is_ref)| match is_ref {
+                true => ValuePart::Reference(text.to_string()),
+                false => ValuePart::Literal(text.to_string()),
+            })
+            .collect();
+        EnvLine::Interpolated {
+            key: key.to_string(),
+            parts,
+        }
+    }
+
+    #[test]
+    fn parse_env_line_cases() {
+        let cases = [
+            // Comments, blank lines and lookups
+            ("", EnvLine::Comment),
+            ("   ", EnvLine::Comment),
+            ("# FOO=bar", EnvLine::Comment),
+            ("API_KEY", lookup("API_KEY", Presence::Default)),
+            // Presence markers
+            ("API_KEY!", lookup("API_KEY", Presence::Required)),
+            ("SENTRY_DSN?", lookup("SENTRY_DSN", Presence::Optional)),
+            ("export API_KEY!", lookup("API_KEY", Presence::Required)),
+            (
+                "DB_PASSWORD! = $PROD_DB",
+                EnvLine::Alias {
+                    key: "DB_PASSWORD".to_string(),
+                    keyring_key: "PROD_DB".to_string(),
+                    presence: Presence::Required,
+                    default: None,
+                },
+            ),
+            // Alias defaults
+            (
+                "LOG_LEVEL=${ALIAS:-debug}",
+                EnvLine::Alias {
+                    key: "LOG_LEVEL".to_string(),
+                    keyring_key: "ALIAS".to_string(),
+                    presence: Presence::Default,
+                    default: Some("debug".to_string()),
+                },
+            ),
+            (
+                "GREETING=${ALIAS:-'hello there'} # comment",
+                EnvLine::Alias {
+                    key: "GREETING".to_string(),
+                    keyring_key: "ALIAS".to_string(),
+                    presence: Presence::Default,
+                    default: Some("hello there".to_string()),
+                },
+            ),
+            (
+                "EMPTY=${ALIAS:-}",
+                EnvLine::Alias {
+                    key: "EMPTY".to_string(),
+                    keyring_key: "ALIAS".to_string(),
+                    presence: Presence::Default,
+                    default: Some(String::new()),
+                },
+            ),
+            // Include directive
+            (
+                "#@include ../.env.shared",
+                EnvLine::Include {
+                    path: "../.env.shared".to_string(),
+                },
+            ),
+            (
+                "#@include \".env shared\"",
+                EnvLine::Include {
+                    path: ".env shared".to_string(),
+                },
+            ),
+            ("#@included", EnvLine::Comment),
+            // Shell `export` prefix
+            ("export API_KEY", lookup("API_KEY", Presence::Default)),
+            ("export FOO=bar", direct("FOO", "bar")),
+            ("export\tFOO=$BAR", alias("FOO", "BAR")),
+            ("  export   FOO=\"a b\"", direct("FOO", "a b")),
+            ("exported=1", direct("exported", "1")),
+            // Unquoted values
+            ("FOO=bar", direct("FOO", "bar")),
+            ("FOO = bar ", direct("FOO", "bar")),
+            ("FOO=", direct("FOO", "")),
+            ("FOO=a b  c", direct("FOO", "a b  c")),
+            ("FOO=value # comment", direct("FOO", "value")),
+            ("FOO=value\t# comment", direct("FOO", "value")),
+            ("FOO= # comment", direct("FOO", "")),
+            ("FOO=value#hash", direct("FOO", "value#hash")),
+            ("FOO=#hash", direct("FOO", "#hash")),
+            ("FOO=C:\\path\\n", direct("FOO", "C:\\path\\n")),
+            ("PRICE=\\$5", direct("PRICE", "$5")),
+            ("FOO=a\\$B", direct("FOO", "a$B")),
+            ("FOO=cost $ 5", direct("FOO", "cost $ 5")),
+            // Aliases
+            ("FOO=$BAR", alias("FOO", "BAR")),
+            ("FOO=${BAR}", alias("FOO", "BAR")),
+            ("FOO=$BAR # comment", alias("FOO", "BAR")),
+            // Single quotes are fully literal
+            ("PS1='$ '", direct("PS1", "$ ")),
+            ("FOO='$BAR'", direct("FOO", "$BAR")),
+            ("FOO='a\\nb ${C}'", direct("FOO", "a\\nb ${C}")),
+            ("FOO='a # b' # comment", direct("FOO", "a # b")),
+            ("FOO=''", direct("FOO", "")),
+            // Double quotes process escapes
+            ("FOO=\"a\\nb\"", direct("FOO", "a\nb")),
+            ("FOO=\"a\\tb\"", direct("FOO", "a\tb")),
+            ("FOO=\"say \\\"hi\\\"\"", direct("FOO", "say \"hi\"")),
+            ("FOO=\"\\$5\"", direct("FOO", "$5")),
+            ("FOO=\"a\\\\b\"", direct("FOO", "a\\b")),
+            ("FOO=\"a\\qb\"", direct("FOO", "a\\qb")),
+            ("FOO=\"a # b\" # comment", direct("FOO", "a # b")),
+            ("FOO=\"\"", direct("FOO", "")),
+            // Interpolation
+            ("FOO=\"$BAR\"", interpolated("FOO", &[("BAR", true)])),
+            (
+                "URL=http://$HOST:${PORT}/x",
+                interpolated(
+                    "URL",
+                    &[
+                        ("http://", false),
+                        ("HOST", true),
+                        (":", false),
+                        ("PORT", true),
+                        ("/x", false),
+                    ],
+                ),
+            ),
+            (
+                "FOO=\"${A}\\n\\$B\"",
+                interpolated("FOO", &[("A", true), ("\n$B", false)]),
+            ),
+        ];
+
+        for (line, expected) in cases {
+            assert_eq!(parse_env_line(line), expected, "parsing {:?}", line);
+            assert_eq!(try_parse_env_line(line), Ok(expected), "parsing {:?}", line);
+        }
+    }
+
+    #[test]
+    fn try_parse_env_line_errors() {
+        let cases = [
+            ("API KEY", 4),
+            ("=foo", 1),
+            ("1ABC=x", 1),
+            ("FOO=$", 5),
+            ("FOO=$BAD-NAME", 9),
+            ("FOO=\"unterminated", 5),
+            ("FOO='unterminated", 5),
+            ("FOO=\"a\" b", 9),
+            ("FOO=a${B", 6),
+            ("FOO=x${bad-name}", 8),
+            ("export BAD KEY=1", 11),
+            ("FOO!=bar", 4),
+            ("FOO!?", 4),
+            ("FOO=${:-x}", 5),
+            ("FOO=${1A:-\"x\"}", 7),
+            ("FOO=${A:-\"$B\"}", 10),
+            ("FOO=${A:-'x'y}", 13),
+            ("FOO=${A:-'x'} y", 15),
+        ];
+
+        for (line, column) in cases {
+            let err = try_parse_env_line(line).expect_err(line);
+            assert_eq!(err.0, column, "column for {:?}: {}", line, err.1);
+        }
+    }
+
+    #[test]
+    fn env_document_round_trips_and_edits() {
+        let content =
+            "# header\r\n\r\nexport FOO=1 # note\r\n  BAR='x'\r\nKEY=\"a\r\nb\"\r\nSECRET";
+        let mut doc = EnvDocument::parse(content);
+        assert_eq!(doc.to_string(), content);
+        assert_eq!(doc.get("KEY"), Some(&direct("KEY", "a\nb")));
+
+        doc.set(direct("FOO", "two words"));
+        doc.set(alias("BAR", "OTHER"));
+        doc.set(direct("NEW", "$5"));
+        assert!(doc.remove("KEY"));
+        doc.move_entry(doc.position("SECRET").unwrap(), 0);
+
+        assert_eq!(
+            doc.to_string(),
+            "SECRET\r\n# header\r\n\r\nexport FOO='two words' # note\r\n  BAR=$OTHER\r\nNEW='$5'\r\n"
+        );
+
+        let reparsed = EnvDocument::parse(&doc.to_string());
+        assert_eq!(reparsed.get("FOO"), Some(&direct("FOO", "two words")));
+        assert_eq!(reparsed.get("NEW"), Some(&direct("NEW", "$5")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn env_document_write_keeps_links_and_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let target = tmp.path().join("shared.env");
+        let link = tmp.path().join(".env");
+        fs::write(&target, "A=1\n").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o600)).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let mut doc = EnvDocument::read(&link).unwrap();
+        doc.set(direct("A", "2"));
+        doc.write(&link).unwrap();
+
+        assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "A=2\n");
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // The temp file is removed when it can't replace the original
+        let dir = tmp.path().join("dir.env");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("keep"), "").unwrap();
+        assert!(doc.write(&dir).is_err());
+        let names: Vec<_> = fs::read_dir(tmp.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names.len(), 3, "{:?}", names);
+    }
+
+    #[test]
+    fn rename_keyring_key_rewrites_every_reference() {
+        let content = "OLD!\nexport A=$OLD # note\nB=${OLD:-x}\nC=$OTHER\nD=\"$OLD\"\nOLD_2\n";
+        let mut doc = EnvDocument::parse(content);
+
+        assert_eq!(doc.rename_keyring_key("OLD", "NEW"), 3);
+        assert_eq!(
+            doc.to_string(),
+            "OLD!=$NEW\nexport A=$NEW # note\nB=${NEW:-x}\nC=$OTHER\nD=\"$OLD\"\nOLD_2\n"
+        );
+
+        let tmp = tempfile::tempdir().unwrap();
+        let files = [".env", ".env.local", ".env.missing"].map(|name| tmp.path().join(name));
+        fs::write(&files[0], "A=$OLD\n").unwrap();
+        fs::write(&files[1], "OLD\nB=1\n").unwrap();
+
+        let err = ReferenceRewrite::new(&files, "OLD", "NEW").err().unwrap();
+        assert!(format!("{:#}", err).contains(".env.missing"));
+        assert_eq!(fs::read_to_string(&files[0]).unwrap(), "A=$OLD\n");
+
+        let written = ReferenceRewrite::new(&files[..2], "OLD", "NEW")
+            .unwrap()
+            .write()
+            .unwrap();
+        assert_eq!(written, [(files[0].clone(), 1), (files[1].clone(), 1)]);
+        assert_eq!(fs::read_to_string(&files[1]).unwrap(), "OLD=$NEW\nB=1\n");
+    }
+
+    #[test]
+    fn interpolate_resolves_in_dependency_order() {
+        use InterpolationError::*;
+        let undefined = |key: &str, reference: &str| Undefined {
+            key: key.to_string(),
+            reference: reference.to_string(),
+        };
+
+        // Each case: templates, keys already resolved, keys declared but
+        // unresolved, and the resolved values in the order they were added
+        type Pairs<'a> = &'a [(&'a str, &'a str)];
+        type Case<'a> = (
+            Pairs<'a>,
+            Pairs<'a>,
+            &'a [&'a str],
+            Result<Pairs<'a>, InterpolationError>,
+        );
+        let cases: [Case; 6] = [
+            // A key extending itself gets the parent environment's value
+            (
+                &[("PATH", "${PATH}:./bin")],
+                &[],
+                &[],
+                Ok(&[("PATH", "/usr/bin:./bin")]),
+            ),
+            (
+                &[("NOPE", "$NOPE/x")],
+                &[],
+                &[],
+                Err(undefined("NOPE", "NOPE")),
+            ),
+            (
+                &[("A", "x$B"), ("B", "y$A")],
+                &[],
+                &[],
+                Err(Cycle(["A", "B", "A"].map(String::from).to_vec())),
+            ),
+            (
+                &[("A", "$MISSING")],
+                &[],
+                &[],
+                Err(undefined("A", "MISSING")),
+            ),
+            (
+                &[("A", "$SECRET")],
+                &[],
+                &["SECRET"],
+                Err(Unresolved {
+                    key: "A".to_string(),
+                    reference: "SECRET".to_string(),
+                }),
+            ),
+            // References can point forward; dependencies resolve first
+            (
+                &[("URL", "${HOST}:${PORT}"), ("HOST", "$NAME.local")],
+                &[("PORT", "80"), ("NAME", "api")],
+                &[],
+                Ok(&[
+                    ("PORT", "80"),
+                    ("NAME", "api"),
+                    ("HOST", "api.local"),
+                    ("URL", "api.local:80"),
+                ]),
+            ),
+        ];
+
+        for (templates, known, unresolved, expected) in cases {
+            let templates: IndexMap<String, Vec<ValuePart>> = templates
+                .iter()
+                .map(|(key, value)| (key.to_string(), parse_template(value).unwrap()))
+                .collect();
+            let mut resolved: IndexMap<String, String> = known
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            let declared: IndexSet<String> = templates
+                .keys()
+                .chain(resolved.keys())
+                .cloned()
+                .chain(unresolved.iter().map(|key| key.to_string()))
+                .collect();
+            let result = interpolate(&templates, &declared, &mut resolved, |name| {
+                (name == "PATH").then(|| "/usr/bin".to_string())
+            });
+            let values: Vec<(&str, &str)> = resolved
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect();
+            match expected {
+                Ok(expected) => assert_eq!((result, values.as_slice()), (Ok(()), expected)),
+                Err(expected) => assert_eq!(result, Err(expected), "{:?}", templates),
+            }
+        }
+    }
+
+    #[test]
+    fn quote_value_round_trips() {
+        for value in [
+            "",
+            "plain",
+            "a b",
+            "$5",
+            "it's",
+            "line\nbreak",
+            "crlf\r\n",
+            "cr\r",
+            "q\"\\$x",
+            "#x",
+            "a}b # c",
+            "multi\nline \"quoted\" ${X}",
+        ] {
+            let line = format_env_line(&direct("K", value));
+            assert_eq!(
+                parse_env_line(&line),
+                direct("K", value),
+                "formatted as {}",
+                line
+            );
+
+            // `${ALIAS:-default}` defaults are quoted the same way
+            let aliased = EnvLine::Alias {
+                key: "K".to_string(),
+                keyring_key: "SECRET".to_string(),
+                presence: Presence::Default,
+                default: Some(value.to_string()),
+            };
+            let line = format_env_line(&aliased);
+            assert_eq!(
+                try_parse_env_line(&line),
+                Ok(aliased),
+                "formatted as {}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn includes_override_in_order_and_detect_cycles() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::write(dir.join("shared/.env.base"), "A=base\nB=base\n").unwrap();
+        fs::write(
+            dir.join(".env.production"),
+            "B=early\n#@include shared/.env.base\nA=prod\n",
+        )
+        .unwrap();
+        fs::write(dir.join(".env.loop"), "#@include .env.loop\n").unwrap();
+
+        let sources = get_env_var_sources_from_file(&dir.join(".env.production")).unwrap();
+        let sources: Vec<_> = sources
+            .iter()
+            .map(|(key, source)| (key.as_str(), source.path.file_name().unwrap(), source.line))
+            .collect();
+        assert_eq!(
+            sources,
+            [
+                ("B", std::ffi::OsStr::new(".env.base"), 2),
+                ("A", std::ffi::OsStr::new(".env.production"), 3),
+            ]
+        );
+
+        let err = try_read_env_file(&dir.join(".env.loop")).unwrap_err();
+        assert!(format!("{:#}", err).contains("include cycle"));
+
+        assert_eq!(
+            included_files(&dir.join(".env.production")),
+            [dir.join("shared/.env.base")]
+        );
+        assert_eq!(
+            included_files(&dir.join(".env.loop")),
+            [dir.join(".env.loop")]
+        );
+    }
+
+    #[test]
+    fn env_layers_merge_from_project_root_down() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let package = root.join("packages/api/src");
+        fs::create_dir_all(&package).unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::write(root.join(".env"), "").unwrap();
+        fs::write(root.join("packages/api/.env.production"), "").unwrap();
+        fs::write(package.join(".env.local"), "").unwrap();
+
+        let dirs = project_dirs(&package).unwrap();
+        assert_eq!(dirs.len(), 4);
+
+        let layers: Vec<_> = env_layers(&dirs, Some("production"))
+            .iter()
+            .map(|path| package.join(path).canonicalize().unwrap())
+            .collect();
+        let expected = [
+            ".env",
+            "packages/api/.env.production",
+            "packages/api/src/.env.local",
+        ]
+        .map(|path| root.join(path).canonicalize().unwrap());
+        assert_eq!(layers, expected);
+
+        let local = env_layers(&dirs, Some("local"));
+        assert_eq!(local.len(), 2);
+    }
+
+    /// Point the keyring at one in-memory store shared by every test, so tests
+    /// running in parallel don't swap it out from under each other. Tests use
+    /// distinct keys.
+    pub(crate) fn mock_keyring() {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            keyring_core::set_default_store(keyring_core::mock::Store::new().unwrap())
+        });
+    }
+
+    #[tokio::test]
+    async fn keyring_lookups_are_deduplicated_in_order() {
+        mock_keyring();
+        add_secret_to_keyring("BATCH_B", "b").unwrap();
+        add_secret_to_keyring("BATCH_A", "a").unwrap();
+
+        let keys = ["BATCH_B", "BATCH_MISSING", "BATCH_A", "BATCH_B"].map(String::from);
+        let secrets = get_secrets_from_keyring(keys).await;
+
+        let values: Vec<_> = secrets
+            .iter()
+            .map(|(key, lookup)| (key.as_str(), lookup.value.as_ref().ok().map(String::as_str)))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("BATCH_B", Some("b")),
+                ("BATCH_MISSING", None),
+                ("BATCH_A", Some("a")),
+            ]
+        );
+    }
+
+    #[test]
+    fn secret_exists_distinguishes_missing_keys() {
+        mock_keyring();
+        add_secret_to_keyring("EXISTS_A", "a").unwrap();
+
+        assert!(secret_exists("EXISTS_A").unwrap());
+        assert!(!secret_exists("EXISTS_MISSING").unwrap());
+        delete_secret_from_keyring("EXISTS_A").unwrap();
+        assert!(!secret_exists("EXISTS_A").unwrap());
+    }
+
+    #[test]
+    fn copy_and_rename_secrets() {
+        mock_keyring();
+        add_secret_to_keyring("MOVE_SRC", "value").unwrap();
+        add_secret_to_keyring("MOVE_TAKEN", "old").unwrap();
+
+        assert_eq!(copy_secret("MOVE_SRC", "MOVE_COPY").unwrap(), None);
+        assert_eq!(get_secret_from_keyring("MOVE_SRC").unwrap(), "value");
+        assert_eq!(get_secret_from_keyring("MOVE_COPY").unwrap(), "value");
+        assert!(copy_secret("MOVE_SRC", "MOVE_SRC").is_err());
+
+        rename_secret("MOVE_COPY", "MOVE_TAKEN").unwrap();
+        assert!(!secret_exists("MOVE_COPY").unwrap());
+        assert_eq!(get_secret_from_keyring("MOVE_TAKEN").unwrap(), "value");
+        assert!(rename_secret("MOVE_MISSING", "MOVE_SRC").is_err());
+        assert_eq!(get_secret_from_keyring("MOVE_SRC").unwrap(), "value");
+    }
+
+    #[test]
+    fn mask_value_hides_all_but_the_ends() {
+        assert_eq!(mask_value("sk_live_51Habc9f2"), "sk_l****9f2");
+        assert_eq!(mask_value("ünïcödé-välüé"), "ünïc****lüé");
+        assert_eq!(mask_value("hunter2"), "****");
+        assert_eq!(mask_value(""), "****");
+    }
+
+    /// Resolve env files with the given contents, lowest precedence first.
+    async fn resolve_files(
+        files: &[(&str, &str)],
+        required: &[&str],
+        no_override: bool,
+    ) -> Resolution {
+        resolve_files_with_parent(files, required, no_override, None).await
+    }
+
+    async fn resolve_files_with_parent(
+        files: &[(&str, &str)],
+        required: &[&str],
+        no_override: bool,
+        parent: Option<&[(String, String)]>,
+    ) -> Resolution {
+        let tmp = tempfile::tempdir().unwrap();
+        let paths: Vec<PathBuf> = files
+            .iter()
+            .map(|(name, content)| {
+                let path = tmp.path().join(name);
+                fs::write(&path, content).unwrap();
+                path
+            })
+            .collect();
+        let required: Vec<String> = required.iter().map(|key| key.to_string()).collect();
+        process_env_file(&paths, &required, no_override, parent, false)
+            .await
+            .unwrap()
+    }
+
+    fn values(vars: &[ResolvedVar]) -> IndexMap<&str, &str> {
+        vars.iter()
+            .map(|var| (var.key.as_str(), var.value.as_str()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn later_layers_win_and_secrets_come_from_the_keyring() {
+        mock_keyring();
+        add_secret_to_keyring("RESOLVE_TOKEN", "t0k3n").unwrap();
+        add_secret_to_keyring("RESOLVE_KEY", "k3y").unwrap();
+
+        let resolution = resolve_files(
+            &[
+                (
+                    ".env",
+                    "A=base\nB=base\nTOKEN=$RESOLVE_TOKEN\nURL=\"${A}/x\"\n",
+                ),
+                (".env.local", "B=local\nRESOLVE_KEY\nA=over\n"),
+            ],
+            &[],
+            false,
+        )
+        .await;
+        assert!(resolution.missing.is_empty());
+        let vars = resolution.into_vars(true).unwrap();
+        let mut values = values(&vars);
+        values.sort_keys();
+        assert_eq!(
+            values.into_iter().collect::<Vec<_>>(),
+            [
+                ("A", "over"),
+                ("B", "local"),
+                ("RESOLVE_KEY", "k3y"),
+                ("TOKEN", "t0k3n"),
+                ("URL", "over/x"),
+            ]
+        );
+
+        let var = |key: &str| vars.iter().find(|var| var.key == key).unwrap();
+        assert!(var("B").source.path.ends_with(".env.local"));
+        assert!(matches!(var("RESOLVE_KEY").origin, Origin::Keyring));
+        assert!(matches!(var("TOKEN").origin, Origin::Alias { .. }));
+        assert!(matches!(var("URL").origin, Origin::Interpolated));
+    }
+
+    #[tokio::test]
+    async fn alias_defaults_are_used_after_the_keyring() {
+        mock_keyring();
+        add_secret_to_keyring("RESOLVE_SET", "from keyring").unwrap();
+
+        let resolution = resolve_files(
+            &[(
+                ".env",
+                "SET=${RESOLVE_SET:-fallback}\nUNSET=${RESOLVE_UNSET:-fallback}\n",
+            )],
+            &[],
+            false,
+        )
+        .await;
+        let vars = resolution.into_vars(true).unwrap();
+        assert_eq!(values(&vars)["SET"], "from keyring");
+        assert_eq!(values(&vars)["UNSET"], "fallback");
+        assert!(matches!(vars[1].origin, Origin::AliasDefault { .. }));
+
+        // Only a secret that does not exist falls back; a keyring that can't
+        // be read is reported rather than quietly replaced by the default
+        let entry = keyring_core::Entry::new(keyring_namespace(), "RESOLVE_LOCKED").unwrap();
+        let mock: &keyring_core::mock::Cred = entry.as_any().downcast_ref().unwrap();
+        let locked = std::io::Error::other("keyring is locked");
+        mock.set_error(keyring_core::Error::NoStorageAccess(Box::new(locked)));
+
+        let resolution = resolve_files(
+            &[(".env", "LOCKED=${RESOLVE_LOCKED:-fallback}\n")],
+            &[],
+            false,
+        )
+        .await;
+        assert_eq!(resolution.missing.len(), 1);
+        assert_eq!(resolution.missing[0].key, "LOCKED");
+        assert!(resolution.vars.is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_secrets_are_fatal_by_presence() {
+        mock_keyring();
+        let resolution = resolve_files(
+            &[(
+                ".env",
+                "RESOLVE_PLAIN\nRESOLVE_OPT?\nRESOLVE_REQ!\nRESOLVE_CONFIG\nSET=1\n",
+            )],
+            &["RESOLVE_UNDEFINED", "RESOLVE_CONFIG"],
+            false,
+        )
+        .await;
+
+        let fatal = |strict| {
+            let fatal = resolution.missing.iter().filter(|m| m.is_fatal(strict));
+            fatal.map(|m| m.key.as_str()).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            fatal(false),
+            ["RESOLVE_UNDEFINED", "RESOLVE_REQ", "RESOLVE_CONFIG"]
+        );
+        assert_eq!(
+            fatal(true),
+            [
+                "RESOLVE_UNDEFINED",
+                "RESOLVE_PLAIN",
+                "RESOLVE_REQ",
+                "RESOLVE_CONFIG"
+            ]
+        );
+
+        let err = resolution.into_vars(false).err().unwrap().to_string();
+        assert!(err.starts_with("3 required secret(s) missing"), "{}", err);
+        assert!(err.contains("RESOLVE_UNDEFINED: not defined in any env file"));
+        assert!(!err.contains("RESOLVE_PLAIN"));
+
+        let resolution = resolve_files(
+            &[(".env", "RESOLVE_PLAIN\nRESOLVE_OPT?\nSET=1\n")],
+            &[],
+            false,
+        )
+        .await;
+        let vars = resolution.into_vars(false).unwrap();
+        assert_eq!(
+            values(&vars).into_iter().collect::<Vec<_>>(),
+            [("SET", "1")]
+        );
+    }
+
+    #[tokio::test]
+    async fn no_override_leaves_parent_variables_out() {
+        assert!(std::env::var_os("PATH").is_some());
+        let files = [(".env", "PATH=/nowhere\nRESOLVE_OWN=1\n")];
+
+        let resolution = resolve_files(&files, &[], true).await;
+        let vars = resolution.into_vars(true).unwrap();
+        assert_eq!(
+            values(&vars).into_iter().collect::<Vec<_>>(),
+            [("RESOLVE_OWN", "1")]
+        );
+
+        let resolution = resolve_files(&files, &[], false).await;
+        let vars = resolution.into_vars(true).unwrap();
+        assert_eq!(values(&vars)["PATH"], "/nowhere");
+    }
+
+    #[tokio::test]
+    async fn pure_references_only_see_the_allowed_parent_variables() {
+        assert!(std::env::var_os("HOME").is_some());
+        let parent = [("PATH".to_string(), "/allowed".to_string())];
+        let parent = Some(&parent[..]);
+
+        let vars =
+            resolve_files_with_parent(&[(".env", "PATH=\"${PATH}:./bin\"\n")], &[], false, parent)
+                .await
+                .into_vars(true)
+                .unwrap();
+        assert_eq!(values(&vars)["PATH"], "/allowed:./bin");
+
+        let resolution =
+            resolve_files_with_parent(&[(".env", "DIR=\"${HOME}/cache\"\n")], &[], false, parent)
+                .await;
+        assert_eq!(
+            resolution.interpolation_error,
+            Some(InterpolationError::Undefined {
+                key: "DIR".to_string(),
+                reference: "HOME".to_string(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn values_extend_the_definitions_they_override() {
+        mock_keyring();
+        add_secret_to_keyring("RESOLVE_API", "https://api").unwrap();
+
+        let resolution = resolve_files(
+            &[
+                (".env", "BIN=/usr/bin\nAPI=$RESOLVE_API\n"),
+                (
+                    ".env.local",
+                    "BIN=\"${BIN}:./bin\"\nAPI=\"${API}/v2\"\nPATH=\"${PATH}:./bin\"\n",
+                ),
+                (".env.test", "BIN=\"${BIN}:./test\"\n"),
+            ],
+            &[],
+            false,
+        )
+        .await;
+        assert!(resolution.missing.is_empty());
+        let vars = resolution.into_vars(true).unwrap();
+        let values = values(&vars);
+        assert_eq!(values["BIN"], "/usr/bin:./bin:./test");
+        assert_eq!(values["API"], "https://api/v2");
+        // Nothing defines PATH below, so it extends the parent's
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(values["PATH"], format!("{}:./bin", path));
+        assert_eq!(values.len(), 3);
+
+        let resolution = resolve_files(
+            &[
+                (".env", "API=$RESOLVE_NO_API\n"),
+                (".env.local", "API=\"${API}/v2\"\n"),
+            ],
+            &[],
+            false,
+        )
+        .await;
+        assert_eq!(resolution.missing[0].key, "API");
+        assert!(
+            resolution.missing[0]
+                .source
+                .as_ref()
+                .unwrap()
+                .path
+                .ends_with(".env")
+        );
+        assert!(resolution.into_vars(false).is_err());
+    }
+}
@@ -1,33 +1,170 @@
-use clap::Parser;
+use anyhow::Context;
+use clap::{Parser, Subcommand};
 use envgg::{
-    EnvLine, get_env_var_names_from_file, get_secret_from_keyring, list_secret_labels,
-    read_env_file, ui,
+    EnvDocument, EnvFileErrors, Origin, Presence, ReferenceRewrite, Resolution, ResolvedVar,
+    add_secret_to_keyring,
+    backup::{self, Backup, KdfParams, RestoreMode, RestorePlan},
+    check_key,
+    config::{CONFIG_FILE_NAME, Config},
+    copy_secret, delete_secret_from_keyring, env_aliases, env_file_path, env_layers, export,
+    find_secret_in_keyring, get_env_var_sources_from_file, get_secret_from_keyring, import,
+    included_files, keyring_namespace, list_env_files, list_secret_labels, mask_value,
+    process_env_file, project_dirs, prompt, rename_secret, run, secret_exists,
+    set_keyring_namespace, ui,
 };
-use futures::stream::{self, StreamExt};
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::process::Command;
+use indexmap::{IndexMap, IndexSet};
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[command(name = "envgg")]
-#[command(about = "Run commands with environment variables from .env, .env.development, .env.staging, or .env.production", long_about = None)]
+#[command(about = "Run commands with environment variables from .env or .env.<environment> files", long_about = None)]
 struct Cli {
-    #[arg(
-        short = 'l',
-        long = "list",
-        help = "List all secrets stored in the `envgg` namespace in system keyring"
-    )]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Old spelling of `envgg list`
+    #[arg(short = 'l', long = "list", hide = true)]
     list: bool,
 
-    #[arg(short = 'o', long = "open", help = "Open the GUI manager")]
+    /// Old spelling of `envgg open`
+    #[arg(short = 'o', long = "open", hide = true)]
     open: bool,
 
+    /// Old spelling of `envgg current`
+    #[arg(short = 'c', long = "current", hide = true)]
+    current: bool,
+
     #[arg(
-        short = 'c',
-        long = "current",
-        help = "Print available environment variable names from suppported .env files in current folder"
+        short = 'e',
+        long = "env",
+        global = true,
+        help = "Environment to load (.env.<env>), instead of detecting it from the first argument"
     )]
-    current: bool,
+    env: Option<String>,
+
+    #[arg(
+        short = 'f',
+        long = "file",
+        global = true,
+        value_name = "FILE",
+        help = "Env file to load instead of the .env layers; repeat to merge several, later files win"
+    )]
+    files: Vec<PathBuf>,
+
+    #[arg(
+        long = "strict",
+        global = true,
+        overrides_with = "no_strict",
+        help = "Abort if any keyring secret not marked optional (`KEY?`) is missing"
+    )]
+    strict: bool,
+
+    #[arg(
+        long = "no-strict",
+        global = true,
+        overrides_with = "strict",
+        help = "Turn off --strict when envgg.toml enables it"
+    )]
+    no_strict: bool,
+
+    #[arg(
+        long = "exec",
+        overrides_with = "no_exec",
+        help = "Replace envgg with the command instead of running it as a child (Unix only)"
+    )]
+    exec: bool,
+
+    #[arg(
+        long = "no-exec",
+        overrides_with = "exec",
+        help = "Turn off --exec when envgg.toml enables it"
+    )]
+    no_exec: bool,
+
+    #[arg(
+        long = "pure",
+        global = true,
+        overrides_with = "no_pure",
+        help = "Clear the environment: pass only the env file variables and PATH, HOME, TERM"
+    )]
+    pure: bool,
+
+    #[arg(
+        long = "no-pure",
+        global = true,
+        overrides_with = "pure",
+        help = "Turn off --pure when envgg.toml enables it"
+    )]
+    no_pure: bool,
+
+    #[arg(
+        long = "allow",
+        global = true,
+        value_name = "VAR",
+        help = "Also pass this parent variable through in --pure mode; repeatable"
+    )]
+    allow: Vec<String>,
+
+    #[arg(
+        long = "no-override",
+        global = true,
+        overrides_with = "override_parent",
+        help = "Keep variables already set in the parent environment instead of the env file values"
+    )]
+    no_override: bool,
+
+    #[arg(
+        long = "override",
+        global = true,
+        overrides_with = "no_override",
+        help = "Turn off --no-override when envgg.toml enables it"
+    )]
+    override_parent: bool,
+
+    #[arg(
+        long = "redact",
+        overrides_with = "no_redact",
+        help = "Mask keyring secrets in the command's stdout and stderr as ****"
+    )]
+    redact: bool,
+
+    #[arg(
+        long = "no-redact",
+        overrides_with = "redact",
+        help = "Turn off --redact when envgg.toml enables it"
+    )]
+    no_redact: bool,
+
+    #[arg(
+        long = "dry-run",
+        help = "Show what would be passed to the command instead of running it"
+    )]
+    dry_run: bool,
+
+    #[arg(
+        long = "reveal",
+        global = true,
+        help = "With --dry-run or show, print full values instead of masking them"
+    )]
+    reveal: bool,
+
+    #[arg(
+        short = 'v',
+        long = "verbose",
+        global = true,
+        overrides_with = "no_verbose",
+        help = "Print which env file and line supplied each variable, and keyring lookup timings"
+    )]
+    verbose: bool,
+
+    #[arg(
+        long = "no-verbose",
+        global = true,
+        overrides_with = "verbose",
+        help = "Turn off --verbose when envgg.toml enables it"
+    )]
+    no_verbose: bool,
 
     #[arg(
         trailing_var_arg = true,
@@ -35,17 +172,270 @@ struct Cli {
         required = false,
         help = "Arguments: [env] command...
 
-Where env is optional and can be: [d, development, s, staging, p, production]
+The first argument is used as the environment when .env.<env> exists,
+unless --file is given or envgg's options end with --.
+Short aliases d, s and p expand to development, staging and production;
+add more with ENVGG_ALIASES=q=qa,pr=preview
 
 Examples:
 envgg npm start             # .env
 envgg development npm start # .env.development
 envgg d npm start           # .env.development
-envgg p tsx src/index.ts    # .env.production"
+envgg qa npm start          # .env.qa
+envgg -e p tsx src/index.ts # .env.production
+envgg -- rm -rf dist        # a command named like a subcommand
+envgg -- test --watch       # a command named like an environment"
     )]
     args: Vec<String>,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Start an agent that caches secrets from the keyring, so later runs skip
+    /// the keyring until the TTL expires or `envgg agent lock` is run (Unix only)
+    Agent {
+        #[command(subcommand)]
+        action: Option<AgentAction>,
+
+        #[arg(
+            long = "ttl",
+            default_value = "15m",
+            value_parser = parse_ttl,
+            help = "How long each secret stays cached, e.g. 90s, 15m or 8h"
+        )]
+        ttl: std::time::Duration,
+
+        #[arg(
+            long = "foreground",
+            help = "Run the agent in this terminal instead of detaching"
+        )]
+        foreground: bool,
+    },
+
+    /// Write every secret in the keyring namespace to a passphrase-encrypted
+    /// file, read from ENVGG_BACKUP_PASSPHRASE or asked for on the terminal
+    Backup {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+    },
+
+    /// Copy a secret in the keyring to a new key
+    Cp {
+        #[arg(value_name = "SRC")]
+        from: String,
+
+        #[arg(value_name = "DST")]
+        to: String,
+
+        #[arg(
+            short = 'y',
+            long = "yes",
+            help = "Replace an existing DST without asking for confirmation"
+        )]
+        yes: bool,
+    },
+
+    /// Print the variable names defined by each .env file in the current
+    /// directory
+    Current,
+
+    /// Print the resolved variables as shell assignments, e.g.
+    /// `eval "$(envgg export p)"`
+    Export {
+        /// Environment to load (.env.<env>)
+        #[arg(id = "environment", value_name = "ENV")]
+        env: Option<String>,
+
+        #[arg(
+            long = "format",
+            value_enum,
+            default_value = "bash",
+            help = "Output format"
+        )]
+        format: export::ExportFormat,
+    },
+
+    /// Print a secret from the keyring exactly as stored, e.g.
+    /// `TOKEN=$(envgg get GITHUB_TOKEN)`
+    Get { key: String },
+
+    /// Print shell integration code, e.g. `eval "$(envgg hook direnv)"` in
+    /// direnvrc to enable `use envgg [env]` in .envrc files
+    Hook {
+        #[arg(value_enum)]
+        target: export::Hook,
+
+        /// Print the files to watch for changes instead
+        #[arg(long = "watch", hide = true)]
+        watch: bool,
+
+        /// With --watch, the environment `use envgg` loads
+        #[arg(
+            id = "environment",
+            value_name = "ENV",
+            requires = "watch",
+            hide = true
+        )]
+        env: Option<String>,
+    },
+
+    /// Move plaintext values from an env file into the keyring, leaving
+    /// keyring lookups in their place
+    Import {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        #[arg(
+            long = "prefix",
+            help = "Store each KEY as <PREFIX>KEY, referenced as KEY=$<PREFIX>KEY"
+        )]
+        prefix: Option<String>,
+
+        #[arg(
+            long = "keys",
+            value_name = "PATTERN",
+            value_delimiter = ',',
+            help = "Keys to import, by name or glob (e.g. 'STRIPE_*'); asks about each key when omitted"
+        )]
+        keys: Vec<glob::Pattern>,
+
+        #[arg(
+            short = 'y',
+            long = "yes",
+            help = "Replace existing keyring secrets without asking for confirmation"
+        )]
+        yes: bool,
+    },
+
+    /// List all secrets stored in the keyring namespace
+    List,
+
+    /// Rename a secret in the keyring; if the old key can't be removed the
+    /// copy is undone
+    Mv {
+        #[arg(value_name = "OLD")]
+        from: String,
+
+        #[arg(value_name = "NEW")]
+        to: String,
+
+        #[arg(
+            short = 'y',
+            long = "yes",
+            help = "Replace an existing NEW without asking for confirmation"
+        )]
+        yes: bool,
+
+        #[arg(
+            long = "rewrite",
+            help = "Also point references to OLD in this project's env files at NEW"
+        )]
+        rewrite: bool,
+    },
+
+    /// Open the GUI manager
+    Open,
+
+    /// Restore secrets from a file written by `envgg backup`
+    Restore {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        #[arg(
+            long = "merge",
+            conflicts_with = "overwrite",
+            help = "Keep keys already in the keyring (the default)"
+        )]
+        merge: bool,
+
+        #[arg(
+            long = "overwrite",
+            help = "Replace keys already in the keyring with the backup's values"
+        )]
+        overwrite: bool,
+
+        #[arg(
+            long = "dry-run",
+            help = "Show what would be added or replaced without changing the keyring"
+        )]
+        dry_run: bool,
+    },
+
+    /// Delete secrets from the keyring
+    Rm {
+        #[arg(required = true, value_name = "KEY")]
+        keys: Vec<String>,
+
+        #[arg(
+            short = 'y',
+            long = "yes",
+            help = "Delete without asking for confirmation"
+        )]
+        yes: bool,
+    },
+
+    /// Store a secret in the keyring, prompting for it without echo, or
+    /// reading it from stdin when piped
+    Set {
+        key: String,
+
+        #[arg(
+            short = 'y',
+            long = "yes",
+            help = "Overwrite an existing secret without asking for confirmation"
+        )]
+        yes: bool,
+    },
+
+    /// Show every variable the environment resolves to, where it comes from,
+    /// and any keys that failed to resolve; values are masked unless --reveal
+    Show {
+        /// Environment to load (.env.<env>)
+        #[arg(id = "environment", value_name = "ENV")]
+        env: Option<String>,
+    },
+
+    /// Start $SHELL with the resolved variables, marking its prompt with the
+    /// environment name
+    Shell {
+        /// Environment to load (.env.<env>)
+        #[arg(id = "environment", value_name = "ENV")]
+        env: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum AgentAction {
+    /// Forget every cached secret; the next run goes back to the keyring
+    Lock,
+    /// Show whether the agent is running and how many secrets it holds
+    Status,
+    /// Stop the agent, forgetting every cached secret
+    Stop,
+}
+
+#[cfg(unix)]
+use envgg::agent::parse_ttl;
+
+#[cfg(not(unix))]
+fn parse_ttl(_: &str) -> Result<std::time::Duration, String> {
+    Err("envgg agent is only supported on Unix".to_string())
+}
+
+/// Whether envgg's own args were ended with `--`, so that all of `args` is
+/// the command. clap drops that `--`, so it's found in the raw args just
+/// before them.
+fn command_after_dashes(args: &[String]) -> bool {
+    let raw: Vec<_> = std::env::args_os().collect();
+    raw.len() > args.len() && raw[raw.len() - args.len() - 1] == "--"
+}
+
+/// The value of a boolean option: `--flag` or `--no-flag`, whichever was
+/// given last, otherwise the default from envgg.toml.
+fn flag(on: bool, off: bool, default: bool) -> bool {
+    on || (default && !off)
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     #[cfg(target_os = "linux")]
@@ -59,151 +449,945 @@ async fn main() -> anyhow::Result<()> {
 
     let cli = Cli::parse();
 
-    // Handle list flag
-    if cli.list {
-        match list_secret_labels() {
-            Ok(secrets) => {
-                for label in secrets {
-                    println!("{}", label);
+    // Project settings from the nearest envgg.toml; CLI flags override its defaults
+    let config = Config::discover(&std::env::current_dir()?)?.unwrap_or_default();
+    if let Some(namespace) = &config.namespace {
+        set_keyring_namespace(namespace);
+    }
+    let defaults = &config.defaults;
+    let strict = flag(cli.strict, cli.no_strict, defaults.strict);
+    let exec = flag(cli.exec, cli.no_exec, defaults.exec);
+    let verbose = flag(cli.verbose, cli.no_verbose, defaults.verbose);
+    let pure = flag(cli.pure, cli.no_pure, defaults.pure);
+    let no_override = flag(cli.no_override, cli.override_parent, defaults.no_override);
+    let redact = flag(cli.redact, cli.no_redact, defaults.redact);
+    if exec && redact {
+        anyhow::bail!("--exec and --redact cannot be used together");
+    }
+    if pure && no_override {
+        anyhow::bail!("--pure and --no-override cannot be used together");
+    }
+
+    // The old -l, -o and -c flags still work in place of their subcommands
+    let command = match cli.command {
+        None if cli.list => Some(Command::List),
+        None if cli.open => Some(Command::Open),
+        None if cli.current => Some(Command::Current),
+        command => command,
+    };
+
+    // Loaded only by the commands that use the env layers
+    let load_project = || -> anyhow::Result<Project> {
+        let parent = pure.then(|| {
+            let allow = config.allow.iter().chain(&cli.allow);
+            run::allowed_parent_vars(allow.map(String::as_str))
+        });
+        Ok(Project {
+            aliases: env_aliases(&config)?,
+            // Env files are looked up from the project root down to the current directory
+            dirs: project_dirs(Path::new("."))?,
+            files: cli.files.clone(),
+            strict,
+            verbose,
+            no_override,
+            parent,
+            config,
+        })
+    };
+
+    match command {
+        Some(Command::List) => {
+            let secrets = list_secret_labels().context("Error listing secrets")?;
+            for label in secrets {
+                println!("{}", label);
+            }
+            return Ok(());
+        }
+        Some(Command::Open) => {
+            // Outside a project the viewer just can't update references
+            let env_files = load_project()
+                .and_then(|p| p.env_files())
+                .unwrap_or_default();
+            ui::open_secrets_viewer(env_files).await;
+            return Ok(());
+        }
+        Some(Command::Current) => return print_current_vars(),
+        Some(Command::Get { key }) => return get_secret(&key),
+        Some(Command::Set { key, yes }) => return set_secret(&key, yes),
+        Some(Command::Rm { keys, yes }) => return remove_secrets(&keys, yes),
+        Some(Command::Cp { from, to, yes }) => {
+            check_move(&from, &to, yes)?;
+            copy_secret(&from, &to)?;
+            eprintln!("Copied {} to {}", from, to);
+            return Ok(());
+        }
+        Some(Command::Mv {
+            from,
+            to,
+            yes,
+            rewrite,
+        }) => {
+            check_move(&from, &to, yes)?;
+            // Every env file is read and edited before the secret moves
+            let rewrite = match rewrite {
+                true => {
+                    let files = load_project()?.env_files()?;
+                    Some(ReferenceRewrite::new(&files, &from, &to).context("No changes made")?)
+                }
+                false => None,
+            };
+            rename_secret(&from, &to)?;
+            eprintln!("Renamed {} to {}", from, to);
+            if let Some(rewrite) = rewrite {
+                let written = rewrite.write().context("Some env files were not updated")?;
+                for (path, renamed) in written {
+                    eprintln!("Updated {} line(s) in {}", renamed, path.display());
                 }
+            }
+            return Ok(());
+        }
+        Some(Command::Import {
+            file,
+            prefix,
+            keys,
+            yes,
+        }) => return import_secrets(&file, prefix.as_deref(), &keys, yes),
+        Some(Command::Backup { file }) => return backup_secrets(&file),
+        Some(Command::Restore {
+            file,
+            merge: _,
+            overwrite,
+            dry_run,
+        }) => {
+            let mode = match overwrite {
+                true => RestoreMode::Overwrite,
+                false => RestoreMode::Merge,
+            };
+            return restore_secrets(&file, mode, dry_run);
+        }
+        Some(Command::Agent {
+            action,
+            ttl,
+            foreground,
+        }) => {
+            #[cfg(unix)]
+            return run_agent(action, ttl, foreground).await;
+
+            #[cfg(not(unix))]
+            anyhow::bail!("envgg agent is only supported on Unix");
+        }
+        Some(Command::Export { env, format }) => {
+            let project = load_project()?;
+            let env = env.or(cli.env).map(|name| project.env_name(&name));
+            let env = env.or_else(|| project.default_env());
+            let resolved = project.resolve(env.as_deref(), "export").await?;
+            let vars: Vec<_> = resolved
+                .into_iter()
+                .map(|var| (var.key, var.value))
+                .collect();
+            print!("{}", export::format_exports(&vars, format));
+            return Ok(());
+        }
+        Some(Command::Show { env }) => {
+            let project = load_project()?;
+            let env = env.or(cli.env).map(|name| project.env_name(&name));
+            let env = env.or_else(|| project.default_env());
+            return project.show(env.as_deref(), cli.reveal).await;
+        }
+        Some(Command::Shell { env }) => {
+            // An interactive shell needs the real terminal, so `redact` from
+            // envgg.toml does not apply to it
+            if cli.redact {
+                anyhow::bail!("--redact cannot be used with envgg shell");
+            }
+            let project = load_project()?;
+            let env = env.or(cli.env).map(|name| project.env_name(&name));
+            let env = env.or_else(|| project.default_env());
+            let mut env_vars = project.parent_vars();
+            let resolved = project.resolve(env.as_deref(), "start shell").await?;
+            env_vars.extend(resolved.into_iter().map(|var| (var.key, var.value)));
+
+            let label = env.as_deref().unwrap_or("default");
+            let status = run::run_shell(label, &env_vars, pure).await?;
+            run::exit_with_status(status)
+        }
+        Some(Command::Hook {
+            target: export::Hook::Direnv,
+            watch,
+            env,
+        }) => {
+            if !watch {
+                print!("{}", export::DIRENV_HOOK);
                 return Ok(());
             }
-            Err(e) => {
-                anyhow::bail!("Error listing secrets: {}", e);
+
+            let project = load_project()?;
+            let env = env.or(cli.env).map(|name| project.env_name(&name));
+            let env = env.or_else(|| project.default_env());
+            for file in project.watch_files(env.as_deref())? {
+                println!("{}", file.display());
             }
+            return Ok(());
         }
+        None => {}
     }
 
-    // Handle open flag
-    if cli.open {
-        ui::open_secrets_viewer().await;
-        return Ok(());
+    let project = load_project()?;
+    let mut env_vars = project.parent_vars();
+
+    let (env, command) = match (&cli.env, cli.args.first()) {
+        // Explicit environment, all args are the command
+        (Some(env), _) => (Some(project.env_name(env)), &cli.args[..]),
+        // First arg is an environment if its env file exists in the project or
+        // envgg.toml declares it, unless --file chose the files or `--` ended
+        // envgg's own args
+        (None, Some(first))
+            if cli.files.is_empty()
+                && !command_after_dashes(&cli.args)
+                && project.has_env(first) =>
+        {
+            (Some(project.env_name(first)), &cli.args[1..])
+        }
+        // No environment specified, all args are the command
+        _ => (project.default_env(), &cli.args[..]),
+    };
+
+    if cli.dry_run {
+        return project.show(env.as_deref(), cli.reveal).await;
     }
 
-    // Handle current flag
-    if cli.current {
-        let mut env_files = vec![
-            PathBuf::from(".env"),
-            PathBuf::from(".env.development"),
-            PathBuf::from(".env.staging"),
-            PathBuf::from(".env.production"),
-        ];
+    if command.is_empty() {
+        anyhow::bail!("Error: No command specified");
+    }
 
-        env_files.retain(|f| f.exists());
+    let resolved = project.resolve(env.as_deref(), "run command").await?;
 
-        if env_files.is_empty() {
-            println!("No .env files found in current directory");
-        } else {
-            println!("{} .env file(s) found", env_files.len());
-            for path in env_files {
-                let Some(name) = path.file_name().and_then(|f| f.to_str()) else {
-                    continue;
-                };
-                if path.exists() {
-                    match get_env_var_names_from_file(&path) {
-                        Ok(var_names) => {
-                            if var_names.is_empty() {
-                                println!("\n{}: No variables", name);
-                            } else {
-                                println!("\n{}:", name);
-                                for var_name in var_names {
+    // Only secrets are masked; plain values from the env files are not
+    let mut secrets = Vec::new();
+    if redact {
+        let from_keyring = resolved
+            .iter()
+            .filter(|var| matches!(var.origin, Origin::Keyring | Origin::Alias { .. }));
+        secrets.extend(from_keyring.map(|var| var.value.clone()));
+    }
+
+    env_vars.extend(resolved.into_iter().map(|var| (var.key, var.value)));
+
+    if exec {
+        #[cfg(unix)]
+        return Err(run::exec_command(command, &env_vars, pure));
+
+        #[cfg(not(unix))]
+        anyhow::bail!("--exec is only supported on Unix");
+    }
+
+    // Execute the command with environment variables, exiting the way it did
+    let status = run::run_command(command, &env_vars, pure, &secrets).await?;
+    run::exit_with_status(status)
+}
+
+/// `envgg current`: the variables each env file in the current directory
+/// defines.
+fn print_current_vars() -> anyhow::Result<()> {
+    let env_files = list_env_files(Path::new("."))?;
+
+    if env_files.is_empty() {
+        println!("No .env files found in current directory");
+    } else {
+        println!("{} .env file(s) found", env_files.len());
+        for path in env_files {
+            let Some(name) = path.file_name().and_then(|f| f.to_str()) else {
+                continue;
+            };
+            if path.exists() {
+                match get_env_var_sources_from_file(&path) {
+                    Ok(var_sources) => {
+                        if var_sources.is_empty() {
+                            println!("\n{}: No variables", name);
+                        } else {
+                            println!("\n{}:", name);
+                            for (var_name, source) in var_sources {
+                                // Show where included variables come from
+                                if source.path == path {
                                     println!("{}", var_name);
+                                } else {
+                                    println!("{} (from {})", var_name, source.path.display());
                                 }
                             }
                         }
-                        Err(e) => {
-                            eprintln!("Error reading {}: {}", name, e);
-                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Error reading {}: {:#}", name, e);
                     }
                 }
             }
-        };
+        }
+    };
 
-        return Ok(());
+    Ok(())
+}
+
+/// `envgg get`: the secret on stdout with nothing added, so it can be piped
+/// or captured.
+fn get_secret(key: &str) -> anyhow::Result<()> {
+    let value = get_secret_from_keyring(key)
+        .with_context(|| format!("Failed to read '{}' from the keyring", key))?;
+    print!("{}", value);
+    // Keep the shell prompt off the value's line when printing to a terminal
+    if std::io::stdout().is_terminal() && !value.ends_with('\n') {
+        println!();
+    }
+    Ok(())
+}
+
+/// `envgg set`: prompt for the value without echo on a terminal, otherwise
+/// read it from stdin. Overwriting asks first unless `yes` is set.
+fn set_secret(key: &str, yes: bool) -> anyhow::Result<()> {
+    check_key(key)?;
+    if secret_exists(key)? {
+        check_overwrite(key, yes)?;
     }
 
-    // Check if first argument is an environment specifier
-    let valid_envs = ["d", "development", "s", "staging", "p", "production"];
-    let (env, command) = if !cli.args.is_empty() && valid_envs.contains(&cli.args[0].as_str()) {
-        // First arg is an environment
-        (Some(cli.args[0].clone()), &cli.args[1..])
+    let mut stdin = std::io::stdin();
+    let value = if stdin.is_terminal() {
+        prompt::read_hidden(&format!("Value for {}: ", key))?
     } else {
-        // No environment specified, all args are the command
-        (None, &cli.args[..])
+        let mut value = String::new();
+        stdin.read_to_string(&mut value)?;
+        // `echo value | envgg set KEY` should not store echo's newline
+        let trimmed = value.strip_suffix('\n').unwrap_or(&value);
+        trimmed.strip_suffix('\r').unwrap_or(trimmed).to_string()
     };
 
-    if command.is_empty() {
-        anyhow::bail!("Error: No command specified");
+    if value.is_empty() {
+        anyhow::bail!("Refusing to store an empty value for {}", key);
+    }
+    add_secret_to_keyring(key, &value)
+        .with_context(|| format!("Failed to store {} in the keyring", key))?;
+    eprintln!("Stored {} in keyring '{}'", key, keyring_namespace());
+    Ok(())
+}
+
+/// `envgg import`: store the chosen direct values from `path` in the
+/// keyring, then rewrite the file to look them up instead. The file is only
+/// changed once every secret is stored.
+fn import_secrets(
+    path: &PathBuf,
+    prefix: Option<&str>,
+    patterns: &[glob::Pattern],
+    yes: bool,
+) -> anyhow::Result<()> {
+    if let Some(prefix) = prefix {
+        import::check_prefix(prefix)?;
+    }
+    let mut doc = EnvDocument::read(path)?;
+    let values = import::direct_values(&doc);
+    if values.is_empty() {
+        println!("No plaintext values to import in {}", path.display());
+        return Ok(());
     }
 
-    // Construct the env file path based on whether an environment was specified
-    let env_path = match env {
-        None => {
-            // No environment specified, use .env
-            PathBuf::from(".env")
+    let interactive = patterns.is_empty();
+    if interactive && !std::io::stdin().is_terminal() {
+        anyhow::bail!("Pass --keys to choose which keys to import without a terminal");
+    }
+    // A typo in --keys shouldn't quietly import less than was asked for
+    let unmatched: Vec<_> = patterns
+        .iter()
+        .filter(|pattern| !values.keys().any(|key| pattern.matches(key)))
+        .map(|pattern| pattern.as_str())
+        .collect();
+    if !unmatched.is_empty() {
+        anyhow::bail!(
+            "No plaintext value in {} matches --keys {}",
+            path.display(),
+            unmatched.join(", ")
+        );
+    }
+
+    let mut chosen = IndexMap::new();
+    for (key, value) in &values {
+        let pick = match interactive {
+            true => prompt::confirm(&format!("Import {} ({})?", key, mask_value(value)))?,
+            false => patterns.iter().any(|pattern| pattern.matches(key)),
+        };
+        if pick {
+            chosen.insert(key.as_str(), value.as_str());
         }
-        Some(env) => {
-            // Normalize short form to long form
-            let env_name = match env.as_str() {
-                "d" => "development",
-                "s" => "staging",
-                "p" => "production",
-                _ => &env,
-            };
-            // Use .env.{environment}
-            PathBuf::from(format!(".env.{}", env_name))
+    }
+    if chosen.is_empty() {
+        println!("Nothing imported");
+        return Ok(());
+    }
+
+    // Settle every overwrite before storing anything, so declining one
+    // leaves the keyring as it was
+    let mut to_store = Vec::new();
+    for (key, value) in &chosen {
+        let keyring_key = import::keyring_key(key, prefix);
+        match find_secret_in_keyring(&keyring_key)? {
+            // Already imported, e.g. by an earlier run that was interrupted
+            Some(existing) if existing == *value => continue,
+            Some(_) => check_overwrite(&keyring_key, yes)?,
+            None => {}
         }
-    };
+        to_store.push((keyring_key, *value));
+    }
+    for (keyring_key, value) in to_store {
+        add_secret_to_keyring(&keyring_key, value)
+            .with_context(|| format!("Failed to store {} in the keyring", keyring_key))?;
+    }
+
+    import::reference_secrets(&mut doc, chosen.keys().copied(), prefix);
+    doc.write(path)?;
+    println!(
+        "Imported {} secret(s) into keyring '{}' and updated {}",
+        chosen.len(),
+        keyring_namespace(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// `envgg backup`: every secret in the namespace, encrypted into a new file
+/// that only the user can read.
+fn backup_secrets(path: &Path) -> anyhow::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut out = options
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+
+    let result = (|| {
+        let passphrase = backup_passphrase(true)?;
+        let keys = list_secret_labels().context("Error listing secrets")?;
+        let backup = Backup::from_keyring(keyring_namespace(), keys)?;
+        let file = backup::encrypt(&backup, &passphrase, KdfParams::default())?;
+        out.write_all(&file)?;
+        Ok::<_, anyhow::Error>(backup.secrets.len())
+    })();
+
+    match result {
+        Ok(count) => {
+            println!(
+                "Backed up {} secret(s) from keyring '{}' to {}",
+                count,
+                keyring_namespace(),
+                path.display()
+            );
+            Ok(())
+        }
+        Err(e) => {
+            // Don't leave an empty or partial backup behind
+            _ = std::fs::remove_file(path);
+            Err(e)
+        }
+    }
+}
+
+/// `envgg restore`: decrypt the backup and add its secrets to the keyring,
+/// listing what happens to each key.
+fn restore_secrets(path: &Path, mode: RestoreMode, dry_run: bool) -> anyhow::Result<()> {
+    let file = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let passphrase = backup_passphrase(false)?;
+    let backup = backup::decrypt(&file, &passphrase)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+
+    let namespace = keyring_namespace();
+    if backup.namespace != namespace {
+        eprintln!(
+            "Note: {} was backed up from keyring '{}', restoring into '{}'",
+            path.display(),
+            backup.namespace,
+            namespace
+        );
+    }
+
+    let plan = RestorePlan::new(&backup, mode)?;
+    let groups = [
+        ("add", &plan.added),
+        ("replace", &plan.replaced),
+        ("keep", &plan.kept),
+        ("unchanged", &plan.unchanged),
+    ];
+    for (action, keys) in groups {
+        for key in keys {
+            println!("{:<9}  {}", action, key);
+        }
+    }
+    if !plan.kept.is_empty() {
+        println!("\nKept keys differ from the backup; pass --overwrite to replace them");
+    }
+
+    if dry_run {
+        println!("\nDry run: the keyring was not changed");
+        return Ok(());
+    }
+    plan.apply(&backup)?;
+    println!(
+        "\nRestored {} secret(s) into keyring '{}'",
+        plan.added.len() + plan.replaced.len(),
+        namespace
+    );
+    Ok(())
+}
 
-    // Read and parse the env file
-    let env_vars = process_env_file(&env_path).await?;
+/// The backup passphrase: `ENVGG_BACKUP_PASSPHRASE` if set, otherwise asked
+/// for on the terminal, twice when it protects a new backup. It must not be
+/// empty either way.
+fn backup_passphrase(new: bool) -> anyhow::Result<String> {
+    if let Ok(passphrase) = std::env::var("ENVGG_BACKUP_PASSPHRASE") {
+        if passphrase.is_empty() {
+            anyhow::bail!("ENVGG_BACKUP_PASSPHRASE must not be empty");
+        }
+        return Ok(passphrase);
+    }
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!("Set ENVGG_BACKUP_PASSPHRASE to use a backup without a terminal");
+    }
 
-    // Execute the command with environment variables
-    Command::new(&command[0])
-        .args(&command[1..])
-        .envs(env_vars)
-        .status()?;
+    let passphrase = prompt::read_hidden("Backup passphrase: ")?;
+    if passphrase.is_empty() {
+        anyhow::bail!("The passphrase must not be empty");
+    }
+    if new && prompt::read_hidden("Repeat passphrase: ")? != passphrase {
+        anyhow::bail!("The passphrases did not match");
+    }
+    Ok(passphrase)
+}
 
+/// `envgg cp` and `envgg mv`: `from` must exist, `to` must be a valid key,
+/// and replacing it is checked with the user first.
+fn check_move(from: &str, to: &str, yes: bool) -> anyhow::Result<()> {
+    check_key(to)?;
+    if !secret_exists(from)? {
+        anyhow::bail!("{} is not in keyring '{}'", from, keyring_namespace());
+    }
+    if from != to && secret_exists(to)? {
+        check_overwrite(to, yes)?;
+    }
     Ok(())
 }
 
-// If duplicate labels exist, the last entry will take precedence
-async fn process_env_file(path: &PathBuf) -> anyhow::Result<Vec<(String, String)>> {
-    let lines = read_env_file(path)?;
-
-    let env_map = stream::iter(lines)
-        .filter_map(|line| async move {
-            match line {
-                EnvLine::Comment => None,
-                EnvLine::Direct { key, value } => Some((key, value)),
-                EnvLine::Alias { key, keyring_key } => {
-                    match get_secret_from_keyring(&keyring_key) {
-                        Ok(secret_value) => Some((key, secret_value)),
-                        Err(e) => {
-                            eprintln!(
-                                "Warning: Failed to get secret for '{}' from keyring: {}",
-                                keyring_key, e
-                            );
-                            eprintln!("Skipping environment variable '{}'.", key);
-                            None
-                        }
-                    }
+/// Before replacing the existing secret `key`, ask on a terminal; without one
+/// `yes` is required.
+fn check_overwrite(key: &str, yes: bool) -> anyhow::Result<()> {
+    if yes {
+        return Ok(());
+    }
+
+    let namespace = keyring_namespace();
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!(
+            "{} already exists in keyring '{}'; pass --yes to overwrite it",
+            key,
+            namespace
+        );
+    }
+    let question = format!(
+        "{} already exists in keyring '{}'. Overwrite?",
+        key, namespace
+    );
+    if !prompt::confirm(&question)? {
+        anyhow::bail!("Cancelled, {} was not changed", key);
+    }
+    Ok(())
+}
+
+/// `envgg rm`: delete every key after checking they all exist, asking first
+/// unless `yes` is set.
+fn remove_secrets(keys: &[String], yes: bool) -> anyhow::Result<()> {
+    let namespace = keyring_namespace();
+    let keys: IndexSet<&String> = keys.iter().collect();
+
+    // Check them all up front, so a typo doesn't leave the rest half deleted
+    let mut missing = Vec::new();
+    for key in &keys {
+        if !secret_exists(key)? {
+            missing.push(key.as_str());
+        }
+    }
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "Not in keyring '{}': {}; nothing was deleted",
+            namespace,
+            missing.join(", ")
+        );
+    }
+
+    if !yes {
+        if !std::io::stdin().is_terminal() {
+            anyhow::bail!("Pass --yes to delete secrets without a terminal to confirm on");
+        }
+        let list: Vec<&str> = keys.iter().map(|key| key.as_str()).collect();
+        let question = format!("Delete {} from keyring '{}'?", list.join(", "), namespace);
+        if !prompt::confirm(&question)? {
+            anyhow::bail!("Cancelled, nothing was deleted");
+        }
+    }
+
+    for key in keys {
+        delete_secret_from_keyring(key)
+            .with_context(|| format!("Failed to delete {} from the keyring", key))?;
+        eprintln!("Deleted {}", key);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn run_agent(
+    action: Option<AgentAction>,
+    ttl: std::time::Duration,
+    foreground: bool,
+) -> anyhow::Result<()> {
+    use envgg::agent::{self, AgentClient};
+
+    let socket = agent::socket_path();
+    let client = AgentClient::connect(&socket).await?;
+    match (action, client) {
+        (None, None) if foreground => {
+            agent::deny_attach();
+            agent::serve(&socket, ttl).await
+        }
+        (None, None) => {
+            // Re-run envgg detached in its own process group, so the agent
+            // outlives this terminal
+            use std::os::unix::process::CommandExt;
+            let child = std::process::Command::new(std::env::current_exe()?)
+                .args(["agent", "--foreground", "--ttl"])
+                .arg(format!("{}s", ttl.as_secs()))
+                .stdin(std::process::Stdio::null())
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .process_group(0)
+                .spawn()?;
+
+            for _ in 0..50 {
+                if AgentClient::connect(&socket).await?.is_some() {
+                    println!(
+                        "envgg agent started (pid {}), caching secrets for {}s",
+                        child.id(),
+                        ttl.as_secs()
+                    );
+                    return Ok(());
                 }
-                EnvLine::Lookup { key } => match get_secret_from_keyring(&key) {
-                    Ok(value) => Some((key, value)),
-                    Err(e) => {
-                        eprintln!(
-                            "Warning: Failed to get secret for '{}' from keyring: {}",
-                            key, e
-                        );
-                        eprintln!("Skipping this environment variable.");
-                        None
-                    }
-                },
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
             }
-        })
-        .collect::<HashMap<_, _>>()
+            anyhow::bail!("envgg agent did not start; run `envgg agent --foreground` to see why")
+        }
+        (None, Some(_)) => anyhow::bail!("envgg agent is already running at {}", socket.display()),
+        (Some(AgentAction::Status), None) => {
+            println!("envgg agent is not running");
+            Ok(())
+        }
+        (Some(_), None) => anyhow::bail!("envgg agent is not running"),
+        (Some(AgentAction::Lock), Some(mut client)) => {
+            client.lock().await?;
+            println!("envgg agent locked: cached secrets forgotten");
+            Ok(())
+        }
+        (Some(AgentAction::Status), Some(mut client)) => {
+            let status = client.status().await?;
+            println!(
+                "envgg agent is running at {}: {} secret(s) cached for up to {}s",
+                socket.display(),
+                status.cached,
+                status.ttl.as_secs()
+            );
+            Ok(())
+        }
+        (Some(AgentAction::Stop), Some(mut client)) => {
+            client.stop().await?;
+            println!("envgg agent stopped");
+            Ok(())
+        }
+    }
+}
+
+/// Where env files come from and how they are resolved, from envgg.toml and
+/// the command line.
+struct Project {
+    config: Config,
+    aliases: IndexMap<String, String>,
+    dirs: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+    strict: bool,
+    verbose: bool,
+    no_override: bool,
+    /// With --pure, the only parent variables the command gets and the env
+    /// files can reference
+    parent: Option<Vec<(String, String)>>,
+}
+
+impl Project {
+    /// The parent variables passed to the command alongside the env files;
+    /// they go first so the env files can override them.
+    fn parent_vars(&self) -> Vec<(String, String)> {
+        self.parent.clone().unwrap_or_default()
+    }
+
+    /// Expand a short alias to its full environment name.
+    fn env_name(&self, name: &str) -> String {
+        self.aliases
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    /// Whether `name` is an environment: its env file (or its `.local`
+    /// override) exists in the project or envgg.toml declares it.
+    fn has_env(&self, name: &str) -> bool {
+        let env = self.env_name(name);
+        let names = [
+            env_file_path(Some(&env)),
+            format!(".env.{}.local", env).into(),
+        ];
+        self.dirs
+            .iter()
+            .any(|dir| names.iter().any(|name| dir.join(name).exists()))
+            || self.config.environments.contains_key(&env)
+    }
+
+    /// The environment used when none is given.
+    fn default_env(&self) -> Option<String> {
+        let env = self.config.defaults.env.as_deref()?;
+        Some(self.env_name(env))
+    }
+
+    /// Every env file in the project: all `.env*` files in the project
+    /// directories, plus any given with --file or listed in envgg.toml.
+    fn env_files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut files = IndexSet::new();
+        let cwd = std::env::current_dir()?;
+        for dir in &self.dirs {
+            files.extend(list_env_files(&cwd.join(dir).canonicalize()?)?);
+        }
+        let listed = self.config.environments.values();
+        let listed = listed.filter_map(|env| env.files.as_ref()).flatten();
+        let listed = listed.map(|file| self.config.root.join(file));
+        for file in self.files.iter().cloned().chain(listed) {
+            let file = std::path::absolute(file)?;
+            if file.is_file() {
+                files.insert(file.canonicalize()?);
+            }
+        }
+        Ok(files.into_iter().collect())
+    }
+
+    /// Every file whose change should reload `env` under direnv: the layers
+    /// for `env` even before they exist, so creating one also triggers a
+    /// reload, every other env file, the files they include, and envgg.toml.
+    fn watch_files(&self, env: Option<&str>) -> anyhow::Result<Vec<PathBuf>> {
+        let mut names = vec![PathBuf::from(".env"), PathBuf::from(".env.local")];
+        if let Some(env) = env {
+            names.push(env_file_path(Some(env)));
+            names.push(format!(".env.{}.local", env).into());
+        }
+
+        let mut files = IndexSet::new();
+        let cwd = std::env::current_dir()?;
+        for dir in &self.dirs {
+            let dir = cwd.join(dir).canonicalize()?;
+            files.extend(names.iter().map(|name| dir.join(name)));
+            files.extend(list_env_files(&dir)?);
+        }
+        // Files given with --file or listed in envgg.toml
+        let listed = self.config.environments.values();
+        let listed = listed.filter_map(|env| env.files.as_ref()).flatten();
+        let listed = listed.map(|file| self.config.root.join(file));
+        for file in self.files.iter().cloned().chain(listed) {
+            files.insert(std::path::absolute(file)?);
+        }
+
+        let included: Vec<PathBuf> = files.iter().flat_map(|file| included_files(file)).collect();
+        files.extend(included);
+        if self.config.root.as_os_str() != "" {
+            files.insert(self.config.root.join(CONFIG_FILE_NAME));
+        }
+        Ok(files.into_iter().collect())
+    }
+
+    /// The env files to load for `env`, lowest precedence first.
+    fn layers(&self, env: Option<&str>) -> anyhow::Result<Vec<PathBuf>> {
+        // A misspelled environment would otherwise quietly load only `.env`
+        if let Some(env) = env
+            && self.files.is_empty()
+            && !self.has_env(env)
+        {
+            anyhow::bail!(
+                "Unknown environment '{}': no {} or .env.{}.local in the project, and envgg.toml does not declare it",
+                env,
+                env_file_path(Some(env)).display(),
+                env
+            );
+        }
+
+        // .env, .env.local, .env.<env>, .env.<env>.local in each directory - later
+        // layers win, unless files are given with --file or listed in envgg.toml
+        let layers = match self.files.is_empty() {
+            false => self.files.clone(),
+            true => self
+                .config
+                .env_files(env)
+                .unwrap_or_else(|| env_layers(&self.dirs, env)),
+        };
+        if layers.is_empty() {
+            anyhow::bail!(
+                "No env file found: expected {} in the current directory or a parent up to the project root",
+                env_file_path(env).display()
+            );
+        }
+        Ok(layers)
+    }
+
+    /// Read and resolve `layers`, refusing to `action` if one is malformed.
+    async fn resolution(&self, layers: &[PathBuf], action: &str) -> anyhow::Result<Resolution> {
+        let result = process_env_file(
+            layers,
+            &self.config.required,
+            self.no_override,
+            self.parent.as_deref(),
+            self.verbose,
+        )
         .await;
+        match result {
+            Ok(resolution) => Ok(resolution),
+            Err(e) => match e.downcast_ref::<EnvFileErrors>() {
+                Some(errors) => {
+                    eprintln!("{}", errors.render());
+                    eprintln!(
+                        "\nerror: could not {} due to {} malformed line(s) in {}",
+                        action,
+                        errors.0.len(),
+                        errors.0[0].path.display()
+                    );
+                    std::process::exit(1);
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Print what `env` resolves to without running anything: each variable
+    /// with its origin, source and (masked) value, then every key that failed.
+    /// Exits with 1 if the command would not have been run.
+    async fn show(&self, env: Option<&str>, reveal: bool) -> anyhow::Result<()> {
+        let layers = self.layers(env)?;
+        let resolution = self.resolution(&layers, "show environment").await?;
+
+        let files: Vec<String> = layers
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        println!(
+            "Environment: {} ({})",
+            env.unwrap_or("default"),
+            files.join(", ")
+        );
+
+        let rows: Vec<[String; 4]> = resolution
+            .vars
+            .iter()
+            .map(|var| {
+                let value = match reveal {
+                    true => var.value.clone(),
+                    false => mask_value(&var.value),
+                };
+                [
+                    var.key.clone(),
+                    value,
+                    var.origin.to_string(),
+                    var.source.to_string(),
+                ]
+            })
+            .collect();
+        if rows.is_empty() {
+            println!("\nNo variables");
+        } else {
+            let header = ["KEY", "VALUE", "ORIGIN", "SOURCE"].map(String::from);
+            let mut widths = [0; 4];
+            for row in std::iter::once(&header).chain(&rows) {
+                for (width, cell) in widths.iter_mut().zip(row) {
+                    *width = (*width).max(cell.chars().count());
+                }
+            }
+            println!();
+            for row in std::iter::once(&header).chain(&rows) {
+                let line = format!(
+                    "{:w0$}  {:w1$}  {:w2$}  {}",
+                    row[0],
+                    row[1],
+                    row[2],
+                    row[3],
+                    w0 = widths[0],
+                    w1 = widths[1],
+                    w2 = widths[2]
+                );
+                println!("{}", line.trim_end());
+            }
+        }
 
-    Ok(env_map.into_iter().collect())
+        if !resolution.missing.is_empty() || resolution.interpolation_error.is_some() {
+            println!("\nUnresolved:");
+            for missing in &resolution.missing {
+                let mut line = missing.key.clone();
+                if missing.key != missing.keyring_key {
+                    line.push_str(&format!(" (keyring key '{}')", missing.keyring_key));
+                }
+                if let Some(source) = &missing.source {
+                    line.push_str(&format!(" at {}", source));
+                }
+                let presence = match missing.presence {
+                    Presence::Required => " [required]",
+                    Presence::Optional => " [optional]",
+                    Presence::Default => "",
+                };
+                println!("  {}: {}{}", line, missing.error, presence);
+            }
+            if let Some(e) = &resolution.interpolation_error {
+                println!("  {}", e);
+            }
+        }
+
+        // Fail the way a real run would
+        let fatal = resolution
+            .missing
+            .iter()
+            .any(|missing| missing.is_fatal(self.strict));
+        if fatal || resolution.interpolation_error.is_some() {
+            std::process::exit(1);
+        }
+
+        Ok(())
+    }
+
+    /// Resolve the env files for `env`, failing if a required secret is
+    /// missing, a value cannot be interpolated, or (with --strict) any
+    /// secret not marked optional is missing.
+    async fn resolve(&self, env: Option<&str>, action: &str) -> anyhow::Result<Vec<ResolvedVar>> {
+        let layers = self.layers(env)?;
+        let resolution = self.resolution(&layers, action).await?;
+
+        for missing in &resolution.missing {
+            if missing.presence == Presence::Default && !missing.is_fatal(self.strict) {
+                eprintln!(
+                    "Warning: Failed to get secret for '{}' from keyring: {}",
+                    missing.keyring_key, missing.error
+                );
+                eprintln!("Skipping environment variable '{}'.", missing.key);
+            }
+        }
+
+        // Report every missing secret at once, before anything is run
+        let vars = resolution.into_vars(self.strict)?;
+        if self.verbose {
+            for var in &vars {
+                eprintln!("envgg: {} from {}", var.key, var.source);
+            }
+        }
+
+        Ok(vars)
+    }
 }
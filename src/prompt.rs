@@ -0,0 +1,46 @@
+use std::io::{self, Write};
+
+/// Ask a yes/no question on the terminal; anything but y or yes is a no.
+pub fn confirm(question: &str) -> io::Result<bool> {
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Prompt for a line on the terminal without echoing what is typed (on Unix),
+/// for secrets that shouldn't end up on screen.
+pub fn read_hidden(prompt: &str) -> io::Result<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+
+    #[cfg(unix)]
+    let original = {
+        // SAFETY: termios is plain data, filled in by tcgetattr before use
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let original = termios;
+        termios.c_lflag &= !libc::ECHO;
+        // SAFETY: termios came from tcgetattr with only ECHO cleared
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) };
+        original
+    };
+
+    let mut line = String::new();
+    let read = io::stdin().read_line(&mut line);
+
+    // SAFETY: restores the settings read above
+    #[cfg(unix)]
+    unsafe {
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original)
+    };
+    // The newline the user typed wasn't echoed
+    eprintln!();
+
+    read?;
+    let line = line.strip_suffix('\n').unwrap_or(&line);
+    Ok(line.strip_suffix('\r').unwrap_or(line).to_string())
+}
@@ -0,0 +1,251 @@
+use std::io::{self, Read, Write};
+
+/// What each secret is replaced with.
+pub const MASK: &[u8] = b"****";
+
+/// A streaming filter that replaces secrets in output with [`MASK`].
+///
+/// Output arrives in arbitrary chunks, so a secret can be split across two
+/// reads. Any trailing bytes that could be the start of a secret are held
+/// back until the next chunk shows whether they are, even if they already
+/// match a shorter secret that the longer one starts with.
+pub struct Redactor {
+    /// Longest first, so a secret containing another is masked whole
+    secrets: Vec<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl Redactor {
+    pub fn new<S: AsRef<str>>(secrets: impl IntoIterator<Item = S>) -> Self {
+        let mut secrets: Vec<Vec<u8>> = secrets
+            .into_iter()
+            .map(|secret| secret.as_ref().as_bytes().to_vec())
+            .filter(|secret| !secret.is_empty())
+            .collect();
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        secrets.dedup();
+
+        Self {
+            secrets,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Filter the next chunk of output, returning what is safe to write now.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(chunk);
+        self.filter(false)
+    }
+
+    /// Filter the bytes still held back once the output has ended.
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.filter(true)
+    }
+
+    fn filter(&mut self, ended: bool) -> Vec<u8> {
+        let buffer = std::mem::take(&mut self.pending);
+
+        let mut output = Vec::with_capacity(buffer.len());
+        let mut i = 0;
+        while i < buffer.len() {
+            let rest = &buffer[i..];
+            let longer = |secret: &Vec<u8>| secret.len() > rest.len() && secret.starts_with(rest);
+            if !ended && self.secrets.iter().any(longer) {
+                // Might be a secret that the next chunk completes
+                self.pending = rest.to_vec();
+                break;
+            } else if let Some(secret) = self.secrets.iter().find(|secret| rest.starts_with(secret))
+            {
+                output.extend_from_slice(MASK);
+                i += secret.len();
+            } else {
+                output.push(buffer[i]);
+                i += 1;
+            }
+        }
+
+        output
+    }
+}
+
+/// Copy `reader` to `writer` until the end of the output, masking secrets.
+/// Each chunk is flushed straight away so interactive output is not delayed.
+pub fn copy_redacted(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    mut redactor: Redactor,
+) -> io::Result<()> {
+    let mut buffer = [0; 8192];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            // A pty reports EIO once the child has closed its side
+            #[cfg(unix)]
+            Err(e) if e.raw_os_error() == Some(libc::EIO) => break,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&redactor.feed(&buffer[..read]))?;
+        writer.flush()?;
+    }
+
+    writer.write_all(&redactor.finish())?;
+    writer.flush()
+}
+
+/// Where the child writes one of its output streams, and where envgg reads
+/// it back from to filter it.
+pub struct OutputPipe {
+    pub child: std::process::Stdio,
+    pub reader: Box<dyn Read + Send>,
+    /// The pseudo-terminal standing in for envgg's own terminal, if any
+    #[cfg(unix)]
+    pub pty: Option<Pty>,
+}
+
+/// A pipe for the child's stdout or stderr. When envgg's own stream is a
+/// terminal, the child gets a pseudo-terminal of the same size instead, so it
+/// keeps its colours and line buffering.
+#[cfg(unix)]
+pub fn output_pipe(
+    stream: &(impl io::IsTerminal + std::os::fd::AsRawFd),
+) -> io::Result<OutputPipe> {
+    if !stream.is_terminal() {
+        return pipe();
+    }
+
+    let terminal = stream.as_raw_fd();
+    let (master, slave) = open_pty(terminal)?;
+    Ok(OutputPipe {
+        child: slave.into(),
+        pty: Some(Pty {
+            master: master.try_clone()?,
+            terminal,
+        }),
+        reader: Box::new(std::fs::File::from(master)),
+    })
+}
+
+/// A pipe for the child's stdout or stderr.
+#[cfg(not(unix))]
+pub fn output_pipe(_stream: &impl io::IsTerminal) -> io::Result<OutputPipe> {
+    pipe()
+}
+
+fn pipe() -> io::Result<OutputPipe> {
+    let (reader, writer) = io::pipe()?;
+    Ok(OutputPipe {
+        child: writer.into(),
+        reader: Box::new(reader),
+        #[cfg(unix)]
+        pty: None,
+    })
+}
+
+/// A pseudo-terminal given to the child, and the terminal it mirrors.
+#[cfg(unix)]
+pub struct Pty {
+    master: std::os::fd::OwnedFd,
+    terminal: std::os::fd::RawFd,
+}
+
+#[cfg(unix)]
+impl Pty {
+    /// Give the pty the terminal's current size.
+    fn sync_size(&self) {
+        use std::os::fd::AsRawFd;
+
+        let size = window_size(self.terminal);
+        // SAFETY: TIOCSWINSZ only reads the winsize passed to it
+        unsafe {
+            libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ, &size);
+        }
+    }
+}
+
+/// Resize `ptys` along with their terminals until the returned task is
+/// aborted.
+#[cfg(unix)]
+pub fn forward_resizes(ptys: Vec<Pty>) -> io::Result<tokio::task::JoinHandle<()>> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut resized = signal(SignalKind::window_change())?;
+    Ok(tokio::spawn(async move {
+        while resized.recv().await.is_some() {
+            for pty in &ptys {
+                pty.sync_size();
+            }
+        }
+    }))
+}
+
+/// The size of the terminal on `fd`, or zero if it is unknown.
+#[cfg(unix)]
+fn window_size(fd: std::os::fd::RawFd) -> libc::winsize {
+    // SAFETY: winsize is plain data; TIOCGWINSZ fills it in or fails, and a
+    // zero size just means the terminal size is unknown
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::ioctl(fd, libc::TIOCGWINSZ, &mut size);
+    }
+    size
+}
+
+#[cfg(unix)]
+fn open_pty(
+    terminal: std::os::fd::RawFd,
+) -> io::Result<(std::os::fd::OwnedFd, std::os::fd::OwnedFd)> {
+    use std::os::fd::FromRawFd;
+
+    let mut size = window_size(terminal);
+    let (mut master, mut slave) = (-1, -1);
+    // SAFETY: openpty writes two new descriptors, which we then own
+    let result = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &raw mut size,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: both descriptors were just opened and are not owned elsewhere
+    unsafe {
+        Ok((
+            std::os::fd::OwnedFd::from_raw_fd(master),
+            std::os::fd::OwnedFd::from_raw_fd(slave),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redactor_masks_secrets_split_across_chunks() {
+        let mut redactor = Redactor::new(["hunter2", "hunter22", ""]);
+        let mut output = redactor.feed(b"pw=hun");
+        output.extend(redactor.feed(b"ter2 and hunter22\nhunt"));
+        output.extend(redactor.feed(b"ing "));
+        output.extend(redactor.feed(b"hunte"));
+        output.extend(redactor.finish());
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "pw=**** and ****\nhunting hunte"
+        );
+
+        // A match on the shorter secret waits to see if the longer one follows
+        let mut redactor = Redactor::new(["hunter2", "hunter22"]);
+        let mut output = redactor.feed(b"a hunter2");
+        output.extend(redactor.feed(b"2 b hunter2"));
+        output.extend(redactor.feed(b" c hunter2"));
+        output.extend(redactor.finish());
+        assert_eq!(String::from_utf8(output).unwrap(), "a **** b **** c ****");
+    }
+}
@@ -0,0 +1,340 @@
+use crate::export::{fish_quote, posix_quote};
+use crate::redact::{self, Redactor};
+use anyhow::Context;
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+
+/// How long the output filters get to drain once the child has exited. A
+/// backgrounded grandchild can keep the output open indefinitely.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Parent variables passed through in `--pure` mode, on top of any
+/// configured with `--allow` or `allow` in envgg.toml.
+pub const PURE_ALLOWLIST: [&str; 3] = ["PATH", "HOME", "TERM"];
+
+/// The allowlisted variables that are set in envgg's own environment.
+pub fn allowed_parent_vars<'a>(extra: impl IntoIterator<Item = &'a str>) -> Vec<(String, String)> {
+    PURE_ALLOWLIST
+        .into_iter()
+        .chain(extra)
+        .filter_map(|name| Some((name.to_string(), std::env::var(name).ok()?)))
+        .collect()
+}
+
+/// Spawn `command` with `env_vars` and wait for it to finish. The child
+/// inherits envgg's environment unless `pure` is set. Any `redact` values are
+/// masked in the child's stdout and stderr. On Unix, SIGTERM sent to envgg is
+/// forwarded to the child so it can shut down cleanly, as are SIGINT and
+/// SIGHUP sent with `kill`. The same signals from the terminal are not: the
+/// terminal has already delivered them to the child.
+pub async fn run_command(
+    command: &[String],
+    env_vars: &[(String, String)],
+    pure: bool,
+    redact: &[String],
+) -> anyhow::Result<ExitStatus> {
+    let mut cmd = Command::new(&command[0]);
+    if pure {
+        cmd.env_clear();
+    }
+
+    // Registered before spawning, so a signal in between is not fatal to envgg
+    let signals = Signals::register()?;
+
+    let mut filters = Vec::new();
+    #[cfg(unix)]
+    let mut resizes = None;
+    if !redact.is_empty() {
+        let mut stdout = redact::output_pipe(&std::io::stdout())?;
+        let mut stderr = redact::output_pipe(&std::io::stderr())?;
+        #[cfg(unix)]
+        {
+            let ptys = [stdout.pty.take(), stderr.pty.take()];
+            resizes = Some(redact::forward_resizes(
+                ptys.into_iter().flatten().collect(),
+            )?);
+        }
+        cmd.stdout(stdout.child).stderr(stderr.child);
+        filters.push(std::thread::spawn({
+            let redactor = Redactor::new(redact);
+            move || redact::copy_redacted(stdout.reader, std::io::stdout(), redactor)
+        }));
+        filters.push(std::thread::spawn({
+            let redactor = Redactor::new(redact);
+            move || redact::copy_redacted(stderr.reader, std::io::stderr(), redactor)
+        }));
+    }
+
+    let child = cmd
+        .args(&command[1..])
+        .envs(env_vars.iter().cloned())
+        .spawn()
+        .with_context(|| format!("Failed to run '{}'", command[0]));
+    // Close envgg's copies of the child's output, so the filters see it end
+    drop(cmd);
+    let status = wait_forwarding_signals(child?, signals).await;
+
+    #[cfg(unix)]
+    if let Some(resizes) = resizes {
+        resizes.abort();
+    }
+    // Give up on output still held open by something the child left running
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    for filter in filters {
+        while !filter.is_finished() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        if filter.is_finished() {
+            _ = filter.join();
+        }
+    }
+    status
+}
+
+/// The signals envgg handles while it waits for a child.
+#[cfg(unix)]
+struct Signals {
+    interrupt: TerminalSignal,
+    terminate: tokio::signal::unix::Signal,
+    hangup: TerminalSignal,
+}
+
+#[cfg(not(unix))]
+struct Signals;
+
+impl Signals {
+    #[cfg(unix)]
+    fn register() -> std::io::Result<Self> {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        Ok(Signals {
+            interrupt: TerminalSignal::register(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+            hangup: TerminalSignal::register(SignalKind::hangup())?,
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn register() -> std::io::Result<Self> {
+        Ok(Signals)
+    }
+}
+
+/// `si_code` for a signal sent with `kill`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SI_USER: libc::c_int = libc::SI_USER;
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+const SI_USER: libc::c_int = 0x10001;
+
+/// A signal that the terminal also sends to the whole foreground process
+/// group, and so to the child. Only the ones sent with `kill` are relayed.
+#[cfg(unix)]
+struct TerminalSignal {
+    signal: tokio::signal::unix::Signal,
+    /// How many arrived from `kill` since the last `recv`
+    sent: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    id: signal_hook_registry::SigId,
+}
+
+#[cfg(unix)]
+impl TerminalSignal {
+    fn register(kind: tokio::signal::unix::SignalKind) -> std::io::Result<Self> {
+        let signal = tokio::signal::unix::signal(kind)?;
+        let sent = std::sync::Arc::<std::sync::atomic::AtomicUsize>::default();
+        let counter = sent.clone();
+        // SAFETY: the action only does an atomic add, which is
+        // async-signal-safe
+        let id = unsafe {
+            signal_hook_registry::register_sigaction(kind.as_raw_value(), move |info| {
+                if info.si_code == SI_USER {
+                    counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                }
+            })
+        }?;
+        Ok(Self { signal, sent, id })
+    }
+
+    /// Wait for the signal, then tell whether any of it came from `kill`.
+    async fn recv(&mut self) -> bool {
+        self.signal.recv().await;
+        self.sent.swap(0, std::sync::atomic::Ordering::SeqCst) > 0
+    }
+}
+
+#[cfg(unix)]
+impl Drop for TerminalSignal {
+    fn drop(&mut self) {
+        signal_hook_registry::unregister(self.id);
+    }
+}
+
+async fn wait_forwarding_signals(
+    mut child: tokio::process::Child,
+    signals: Signals,
+) -> anyhow::Result<ExitStatus> {
+    #[cfg(unix)]
+    {
+        let mut signals = signals;
+        loop {
+            // The child shares envgg's process group, so Ctrl-C and hangups
+            // from the terminal already reached it; relaying them as well
+            // would deliver them twice
+            let sig = tokio::select! {
+                status = child.wait() => return Ok(status?),
+                sent = signals.interrupt.recv() => sent.then_some(libc::SIGINT),
+                _ = signals.terminate.recv() => Some(libc::SIGTERM),
+                sent = signals.hangup.recv() => sent.then_some(libc::SIGHUP),
+            };
+            let Some(sig) = sig else {
+                continue;
+            };
+
+            if let Some(pid) = child.id() {
+                // SAFETY: plain syscall on the pid of a child we have not reaped yet
+                unsafe {
+                    libc::kill(pid as libc::pid_t, sig);
+                }
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let Signals = signals;
+        // Ctrl-C reaches the child through the shared console, so envgg
+        // just keeps waiting for it to exit
+        loop {
+            tokio::select! {
+                status = child.wait() => return Ok(status?),
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+    }
+}
+
+/// Start the user's `$SHELL` with `env_vars` and wait for it to exit. The
+/// shell sees `ENVGG_ENV=<label>`, and for bash, zsh and fish (or any shell
+/// that reads `PS1`) its prompt is prefixed with `(envgg:<label>)`.
+pub async fn run_shell(
+    label: &str,
+    env_vars: &[(String, String)],
+    pure: bool,
+) -> anyhow::Result<ExitStatus> {
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| default_shell());
+    let marker = format!("(envgg:{}) ", label);
+    let mut command = vec![shell.clone()];
+    let mut env_vars = env_vars.to_vec();
+    env_vars.push(("ENVGG_ENV".to_string(), label.to_string()));
+
+    // Startup files that load the user's own and then add the marker, in a
+    // new directory only the user can access
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("envgg-shell-");
+    #[cfg(unix)]
+    builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o700));
+    let dir = builder.tempdir()?;
+    let name = std::path::Path::new(&shell)
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    match name {
+        "bash" => {
+            let rcfile = dir.path().join("bashrc");
+            std::fs::write(
+                &rcfile,
+                format!(
+                    "[ -f ~/.bashrc ] && . ~/.bashrc\nPS1={}\"$PS1\"\n",
+                    posix_quote(&marker)
+                ),
+            )?;
+            command.push("--rcfile".to_string());
+            command.push(rcfile.to_string_lossy().into_owned());
+        }
+        "zsh" => {
+            let zdotdir = std::env::var("ZDOTDIR")
+                .or_else(|_| std::env::var("HOME"))
+                .unwrap_or_default();
+            std::fs::write(
+                dir.path().join(".zshenv"),
+                "[ -f \"$ENVGG_ZDOTDIR/.zshenv\" ] && . \"$ENVGG_ZDOTDIR/.zshenv\"\n",
+            )?;
+            std::fs::write(
+                dir.path().join(".zshrc"),
+                format!(
+                    "[ -f \"$ENVGG_ZDOTDIR/.zshrc\" ] && . \"$ENVGG_ZDOTDIR/.zshrc\"\n\
+                     ZDOTDIR=\"$ENVGG_ZDOTDIR\"\nunset ENVGG_ZDOTDIR\nPROMPT={}\"$PROMPT\"\n",
+                    posix_quote(&marker)
+                ),
+            )?;
+            env_vars.push(("ENVGG_ZDOTDIR".to_string(), zdotdir));
+            env_vars.push((
+                "ZDOTDIR".to_string(),
+                dir.path().to_string_lossy().into_owned(),
+            ));
+        }
+        "fish" => {
+            command.push("--init-command".to_string());
+            command.push(format!(
+                "functions -c fish_prompt _envgg_fish_prompt; \
+                 function fish_prompt; echo -n {}; _envgg_fish_prompt; end",
+                fish_quote(&marker)
+            ));
+        }
+        _ => {
+            let prompt = std::env::var("PS1").unwrap_or_else(|_| "$ ".to_string());
+            env_vars.push(("PS1".to_string(), marker + &prompt));
+        }
+    }
+
+    let status = run_command(&command, &env_vars, pure, &[]).await;
+    _ = dir.close();
+    status
+}
+
+fn default_shell() -> String {
+    match cfg!(windows) {
+        true => std::env::var("COMSPEC").unwrap_or_else(|_| "cmd.exe".to_string()),
+        false => "/bin/sh".to_string(),
+    }
+}
+
+/// Exit with the child's status: its exit code or, on Unix, by re-raising
+/// the signal that terminated it.
+pub fn exit_with_status(status: ExitStatus) -> ! {
+    #[cfg(unix)]
+    if let Some(sig) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        // SAFETY: restoring the default disposition and raising a signal on
+        // ourselves; nothing else runs after this point
+        unsafe {
+            libc::signal(sig, libc::SIG_DFL);
+            libc::raise(sig);
+        }
+        // Only reached if the signal does not terminate by default
+        std::process::exit(128 + sig);
+    }
+
+    std::process::exit(status.code().unwrap_or(1))
+}
+
+/// Replace the envgg process with `command`, so no envgg process lingers
+/// between the shell and the command. Only returns on failure.
+#[cfg(unix)]
+pub fn exec_command(
+    command: &[String],
+    env_vars: &[(String, String)],
+    pure: bool,
+) -> anyhow::Error {
+    use std::os::unix::process::CommandExt;
+
+    let mut cmd = std::process::Command::new(&command[0]);
+    if pure {
+        cmd.env_clear();
+    }
+    let err = cmd
+        .args(&command[1..])
+        .envs(env_vars.iter().cloned())
+        .exec();
+
+    anyhow::Error::new(err).context(format!("Failed to exec '{}'", command[0]))
+}
@@ -1,4 +1,7 @@
-use crate::{add_secret_to_keyring, delete_secret_from_keyring, get_secret_from_keyring};
+use crate::{
+    ReferenceRewrite, add_secret_to_keyring, copy_secret, delete_secret_from_keyring,
+    get_secret_from_keyring, rename_secret, secret_exists,
+};
 use gpui::{
     App, AppContext, Bounds, Context, Entity, FocusHandle, Focusable, InteractiveElement,
     IntoElement, ParentElement, Render, SharedString, Size, Styled, Window, WindowBounds,
@@ -7,6 +10,7 @@ use gpui::{
 use gpui_component::{
     ActiveTheme, IconName, Root, Sizable, StyledExt, Theme, ThemeMode, TitleBar, WindowExt,
     button::{Button, ButtonVariants},
+    checkbox::Checkbox,
     h_flex,
     input::{Input, InputState},
     label::Label,
@@ -14,6 +18,7 @@ use gpui_component::{
     v_flex,
 };
 use gpui_component_assets::Assets;
+use std::path::PathBuf;
 
 #[derive(IntoElement)]
 struct SecretListItem {
@@ -91,6 +96,19 @@ impl gpui::RenderOnce for SecretListItem {
                                         });
                                     })
                             })
+                            .child({
+                                let name = name.clone();
+                                let viewer = viewer.clone();
+                                Button::new(SharedString::from(format!("move-{}", name)))
+                                    .icon(IconName::Replace)
+                                    .small()
+                                    .tooltip("Rename or duplicate")
+                                    .on_click(move |_, window, cx| {
+                                        viewer.update(cx, |v, cx| {
+                                            v.open_move_dialog(name.clone(), window, cx);
+                                        });
+                                    })
+                            })
                             .child({
                                 Button::new(SharedString::from(format!("delete-{}", name)))
                                     .icon(IconName::Close)
@@ -196,10 +214,19 @@ impl ListDelegate for SecretListDelegate {
 pub struct SecretsViewer {
     focus_handle: FocusHandle,
     secrets_list: Entity<ListState<SecretListDelegate>>,
+    /// Env files of the project the viewer was opened from, whose references
+    /// a rename can update
+    env_files: Vec<PathBuf>,
+    rewrite_references: bool,
 }
 
 impl SecretsViewer {
-    pub fn new(secrets: Vec<String>, window: &mut Window, cx: &mut Context<Self>) -> Self {
+    pub fn new(
+        secrets: Vec<String>,
+        env_files: Vec<PathBuf>,
+        window: &mut Window,
+        cx: &mut Context<Self>,
+    ) -> Self {
         let viewer = cx.entity().clone();
         let delegate = SecretListDelegate::new(secrets, viewer);
         let secrets_list = cx.new(|cx| ListState::new(delegate, window, cx).searchable(true));
@@ -207,11 +234,18 @@ impl SecretsViewer {
         Self {
             focus_handle: cx.focus_handle(),
             secrets_list,
+            env_files,
+            rewrite_references: false,
         }
     }
 
-    pub fn view(secrets: Vec<String>, window: &mut Window, cx: &mut App) -> Entity<Self> {
-        cx.new(|cx| Self::new(secrets, window, cx))
+    pub fn view(
+        secrets: Vec<String>,
+        env_files: Vec<PathBuf>,
+        window: &mut Window,
+        cx: &mut App,
+    ) -> Entity<Self> {
+        cx.new(|cx| Self::new(secrets, env_files, window, cx))
     }
 
     fn show_delete_confirmation(
@@ -298,6 +332,150 @@ impl SecretsViewer {
         task.detach();
     }
 
+    fn open_move_dialog(&mut self, name: String, window: &mut Window, cx: &mut Context<Self>) {
+        let view = cx.entity().clone();
+        let new_key_input = cx.new(|cx| {
+            InputState::new(window, cx)
+                .placeholder("New key name")
+                .default_value(name.clone())
+        });
+
+        window.open_dialog(cx, move |dialog, _window, cx| {
+            let view = view.clone();
+            let name = name.clone();
+            let key_input = new_key_input.clone();
+
+            let viewer = view.read(cx);
+            let references = (!viewer.env_files.is_empty()).then(|| {
+                let view = view.clone();
+                Checkbox::new("rewrite-references")
+                    .label(format!(
+                        "Update references in {} env file(s) when renaming",
+                        viewer.env_files.len()
+                    ))
+                    .checked(viewer.rewrite_references)
+                    .on_click(move |checked, _, cx| {
+                        view.update(cx, |this, cx| {
+                            this.rewrite_references = *checked;
+                            cx.notify();
+                        });
+                    })
+            });
+
+            dialog
+                .title(format!("Rename or Duplicate '{}'", name))
+                .child(
+                    v_flex()
+                        .gap_2()
+                        .child(Label::new("New Key Name"))
+                        .child(Input::new(&new_key_input))
+                        .children(references),
+                )
+                .footer(move |_, _, _, _| {
+                    Self::create_move_dialog_buttons(view.clone(), name.clone(), key_input.clone())
+                })
+        });
+    }
+
+    fn create_move_dialog_buttons(
+        view: Entity<Self>,
+        name: String,
+        key_input: Entity<InputState>,
+    ) -> Vec<Button> {
+        let action = |id: &'static str, label: &'static str, duplicate: bool| {
+            let view = view.clone();
+            let name = name.clone();
+            let key_input = key_input.clone();
+            Button::new(id).label(label).on_click(move |_, window, cx| {
+                let new_name = key_input.read(cx).text().to_string();
+
+                if !Self::is_valid_env_var_name(&new_name) {
+                    window.push_notification(
+                        "Key must be in SCREAMING_CASE (uppercase letters, numbers, and underscores only, starting with a letter)",
+                        cx,
+                    );
+                    return;
+                }
+
+                window.close_dialog(cx);
+
+                let name = name.clone();
+                view.update(cx, move |this, cx| {
+                    this.handle_move_secret(name, new_name, duplicate, window, cx);
+                });
+            })
+        };
+
+        vec![
+            Button::new("cancel")
+                .label("Cancel")
+                .on_click(move |_, window, cx| {
+                    window.close_dialog(cx);
+                }),
+            action("duplicate", "Duplicate", true),
+            action("rename", "Rename", false).primary(),
+        ]
+    }
+
+    fn handle_move_secret(
+        &mut self,
+        name: String,
+        new_name: String,
+        duplicate: bool,
+        window: &mut Window,
+        cx: &mut Context<Self>,
+    ) {
+        let env_files = match self.rewrite_references && !duplicate {
+            true => self.env_files.clone(),
+            false => Vec::new(),
+        };
+        let task = cx.spawn_in(window, async move |view_entity, window| {
+            // Never replace a secret from the GUI; the CLI asks first
+            let result = match secret_exists(&new_name) {
+                Ok(true) => Err(anyhow::anyhow!("'{}' already exists", new_name)),
+                Ok(false) if duplicate => copy_secret(&name, &new_name).map(drop),
+                Ok(false) => Self::rename_with_references(&name, &new_name, &env_files),
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(()) => {
+                    let operation = match duplicate {
+                        true => format!("duplicated as '{}'", new_name),
+                        false => format!("renamed to '{}'", new_name),
+                    };
+                    Self::refresh_secrets_with_notification(view_entity, window, name, &operation)
+                        .await;
+                }
+                Err(e) => {
+                    let operation = if duplicate { "duplicating" } else { "renaming" };
+                    Self::show_error_notification(
+                        view_entity,
+                        window,
+                        format!("Error {} secret: {:#}", operation, e),
+                    )
+                    .await;
+                }
+            }
+        });
+        task.detach();
+    }
+
+    /// Rename a secret, then point the references in `env_files` at the new
+    /// name. Every file is read first so a bad one leaves the secret alone.
+    fn rename_with_references(
+        name: &str,
+        new_name: &str,
+        env_files: &[PathBuf],
+    ) -> anyhow::Result<()> {
+        let rewrite = ReferenceRewrite::new(env_files, name, new_name)?;
+        rename_secret(name, new_name)?;
+        rewrite
+            .write()
+            .map(drop)
+            .map_err(|e| e.context("Renamed, but some env files were not updated"))
+    }
+
     async fn refresh_secrets_with_notification(
         view_entity: gpui::WeakEntity<Self>,
         window: &mut gpui::AsyncWindowContext,
@@ -545,7 +723,7 @@ impl Render for AppRoot {
     }
 }
 
-pub async fn open_secrets_viewer() {
+pub async fn open_secrets_viewer(env_files: Vec<PathBuf>) {
     let secrets = match crate::list_secret_labels() {
         Ok(secrets) => secrets,
         Err(e) => {
@@ -588,7 +766,7 @@ pub async fn open_secrets_viewer() {
             };
 
             let window = cx.open_window(options, |window, cx| {
-                let view = SecretsViewer::view(secrets, window, cx);
+                let view = SecretsViewer::view(secrets, env_files, window, cx);
                 let root = cx.new(|_cx| AppRoot::new(view));
 
                 cx.new(|cx| Root::new(root, window, cx))